@@ -0,0 +1,49 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{SecondsFormat, Utc};
+use serde_json;
+
+// `bytes_out` is only known for empty bodies and responses carrying a
+// Content-Length; streamed bodies are logged with null.
+#[derive(Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: String,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub bytes_out: Option<u64>
+}
+
+impl AccessLogEntry {
+    pub fn now(request_id: String, method: String, path: String, status: u16, duration_ms: u64, bytes_out: Option<u64>) -> AccessLogEntry {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        AccessLogEntry { timestamp, request_id, method, path, status, duration_ms, bytes_out }
+    }
+}
+
+// One JSON object per line. Each line is written with a single call while
+// holding the lock, so lines from concurrent requests never interleave.
+pub struct AccessLog {
+    file: Mutex<File>
+}
+
+impl AccessLog {
+    pub fn open(path: &Path) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog { file: Mutex::new(file) })
+    }
+
+    // Failures to log are reported but never fail the request.
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let mut line = serde_json::to_vec(entry).unwrap();
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = file.write_all(&line) {
+            println!("unable to write access log: {}", err);
+        }
+    }
+}
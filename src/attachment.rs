@@ -1,28 +1,166 @@
 use std::{error, fmt};
 use std::io::{self, Read};
 use std::convert::From;
-use std::path::PathBuf;
-use std::fs::File;
+use std::fmt::Write as FmtWrite;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::thread;
 use serde_json;
+use hyper::{Body, Chunk};
+use futures::{Future, Sink};
 use base64;
 use mime::{self, Mime};
 use regex::Regex;
+use sha2::Sha256;
+use digest::{Input, FixedOutput};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use store::Storage;
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Attachment content lives once per web in `blobs/`, named by its SHA-256;
+// the file in a page's attachments directory is a pointer holding
+// `sha256:<hash>`, optionally followed by a line with the MIME type the
+// uploader gave. Files that are not pointers are served as they are.
+pub const BLOBS_DIRECTORY: &'static str = "blobs";
+const POINTER_PREFIX: &'static str = "sha256:";
+const HASH_LENGTH: usize = 64;
+const MAX_MIME_TYPE_LENGTH: usize = 255;
+
+// A resumable upload collects in a hidden file beside the attachment
+// until its last byte arrives. The size given by its first chunk is kept
+// in another, so later chunks cannot change it.
+const PART_SUFFIX: &'static str = ".part";
+const TOTAL_SUFFIX: &'static str = ".total";
+
+pub fn content_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.process(data);
+    let mut hash = String::new();
+    for byte in hasher.fixed_result() {
+        write!(&mut hash, "{:02x}", byte).expect("Unable to write");
+    }
+    hash
+}
+
+// `content_hash` of a file, read a chunk at a time.
+pub fn file_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buf = vec![0; STREAM_CHUNK_SIZE];
+    loop {
+        let count = file.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        hasher.process(&buf[..count]);
+    }
+    let mut hash = String::new();
+    for byte in hasher.fixed_result() {
+        write!(&mut hash, "{:02x}", byte).expect("Unable to write");
+    }
+    Ok(hash)
+}
+
+pub fn part_file_name(file_name: &str) -> String {
+    format!(".{}{}", file_name, PART_SUFFIX)
+}
+
+pub fn total_file_name(part_name: &str) -> String {
+    format!("{}{}", part_name, TOTAL_SUFFIX)
+}
+
+// For a file belonging to a resumable upload, the name of its part file.
+pub fn upload_part_name(name: &str) -> Option<&str> {
+    if !name.starts_with('.') {
+        return None;
+    }
+    let part_name = if name.ends_with(TOTAL_SUFFIX) { &name[..name.len() - TOTAL_SUFFIX.len()] } else { name };
+    if part_name.len() > 1 + PART_SUFFIX.len() && part_name.ends_with(PART_SUFFIX) {
+        Some(part_name)
+    } else {
+        None
+    }
+}
+
+pub fn pointer_data(hash: &str, mime_type: Option<&Mime>) -> String {
+    match mime_type {
+        Some(mime_type) => format!("{}{}\n{}", POINTER_PREFIX, hash, mime_type),
+        None => format!("{}{}", POINTER_PREFIX, hash)
+    }
+}
+
+// A MIME type given by an uploader, if it parses as one. Parameters are
+// dropped: quoted values may hold anything, and they are printed back
+// as given.
+pub fn parse_mime_type(value: &str) -> Option<Mime> {
+    if value.len() > MAX_MIME_TYPE_LENGTH || value.chars().any(|c| c.is_control()) {
+        return None;
+    }
+    let mime_type: Mime = value.parse().ok()?;
+    mime_type.essence_str().parse().ok()
+}
+
+// The hash and any MIME type in a pointer file, if `path` is a pointer.
+fn read_pointer_file(path: &Path) -> Option<(String, Option<Mime>)> {
+    let metadata = fs::metadata(path).ok()?;
+    let length = (POINTER_PREFIX.len() + HASH_LENGTH) as u64;
+    if !metadata.is_file() || metadata.len() < length || metadata.len() > length + 1 + MAX_MIME_TYPE_LENGTH as u64 {
+        return None;
+    }
+    let mut data = String::new();
+    File::open(path).ok()?.read_to_string(&mut data).ok()?;
+    if !data.starts_with(POINTER_PREFIX) {
+        return None;
+    }
+    let mut lines = data[POINTER_PREFIX.len()..].splitn(2, '\n');
+    let hash = lines.next()?;
+    if hash.len() != HASH_LENGTH || !hash.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+        return None;
+    }
+    let mime_type = match lines.next() {
+        Some(line) => Some(parse_mime_type(line)?),
+        None => None
+    };
+    Some((hash.to_string(), mime_type))
+}
+
+// The hash a pointer file refers to, if `path` is a pointer.
+pub fn read_pointer(path: &Path) -> Option<String> {
+    read_pointer_file(path).map(|(hash, _)| hash)
+}
 
 #[derive(Debug)]
 pub enum AttachmentError {
     NotFound,
+    InvalidName,
+    OverwriteError,
     IoError(io::Error),
     JsonError(serde_json::error::Error),
     Base64Error(base64::DecodeError),
+    // an upload chunk that does not start where the received bytes end
+    UnexpectedOffset(u64),
+    // a pointer whose blob is gone
+    MissingBlob(String),
+    // an upload chunk giving a different size than the upload's first
+    TotalMismatch(u64),
+    Symlink,
 }
 
 impl error::Error for AttachmentError {
     fn description(&self) -> &str {
         match self {
             &AttachmentError::NotFound => "page not found",
+            &AttachmentError::InvalidName => "attachment name is not valid",
+            &AttachmentError::OverwriteError => "attachment already exists",
             &AttachmentError::IoError(ref err) => err.description(),
             &AttachmentError::JsonError(ref err) => err.description(),
             &AttachmentError::Base64Error(ref err) => err.description(),
+            &AttachmentError::UnexpectedOffset(_) => "upload chunk does not follow the bytes received",
+            &AttachmentError::MissingBlob(_) => "attachment content is missing",
+            &AttachmentError::TotalMismatch(_) => "upload chunk gives a different total size",
+            &AttachmentError::Symlink => "attachment path is a symbolic link",
         }
     }
 }
@@ -31,9 +169,15 @@ impl fmt::Display for AttachmentError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             &AttachmentError::NotFound => write!(f, "AttachmentError::NotFound"),
+            &AttachmentError::InvalidName => write!(f, "AttachmentError::InvalidName"),
+            &AttachmentError::OverwriteError => write!(f, "AttachmentError::OverwriteError"),
             &AttachmentError::IoError(ref err) => write!(f, "AttachmentError::IoError({})", err),
             &AttachmentError::JsonError(ref err) => write!(f, "AttachmentError::JsonError({})", err),
             &AttachmentError::Base64Error(ref err) => write!(f, "AttachmentError::Base64Error({})", err),
+            &AttachmentError::UnexpectedOffset(offset) => write!(f, "AttachmentError::UnexpectedOffset({})", offset),
+            &AttachmentError::MissingBlob(ref hash) => write!(f, "AttachmentError::MissingBlob({})", hash),
+            &AttachmentError::TotalMismatch(total) => write!(f, "AttachmentError::TotalMismatch({})", total),
+            &AttachmentError::Symlink => write!(f, "AttachmentError::Symlink"),
         }
     }
 }
@@ -59,26 +203,108 @@ impl From<base64::DecodeError> for AttachmentError {
     }
 }
 
+// A single path component with an extension. Leading dots are refused
+// so attachments cannot be hidden or step outside their directory.
+pub fn is_valid_file_name(name: &str) -> bool {
+    lazy_static! {
+        static ref FILE_NAME_RE: Regex = Regex::new(r"^.+\.\w+$").unwrap();
+    }
+    FILE_NAME_RE.is_match(name) &&
+        !name.starts_with('.') &&
+        !name.contains('/') &&
+        !name.contains('\\') &&
+        !name.chars().any(|c| c.is_control())
+}
+
+// `path` is the attachment's own file; `data_path` is where its content
+// is read from, which is the blob when `path` is a pointer to one.
+// `given_mime_type` is the type recorded in such a pointer.
 pub struct Attachment {
-    pub path: PathBuf
+    pub path: PathBuf,
+    data_path: PathBuf,
+    given_mime_type: Option<Mime>
 }
 
 impl Attachment {
-    pub fn open(path: PathBuf) -> Result<Attachment, AttachmentError> {
+    pub fn open(path: PathBuf, blobs_path: &Path, storage: &Storage) -> Result<Attachment, AttachmentError> {
+        if storage.refuses_link(&path) {
+            return Err(AttachmentError::Symlink);
+        }
         if !path.exists() {
             return Err(AttachmentError::NotFound);
         }
-        Ok(Attachment { path })
+        match read_pointer_file(&path) {
+            Some((hash, given_mime_type)) => {
+                let data_path = blobs_path.join(&hash);
+                if storage.refuses_link(&data_path) {
+                    return Err(AttachmentError::Symlink);
+                }
+                if !data_path.is_file() {
+                    return Err(AttachmentError::MissingBlob(hash));
+                }
+                Ok(Attachment { path, data_path, given_mime_type })
+            },
+            None => Ok(Attachment { data_path: path.clone(), path, given_mime_type: None })
+        }
     }
 
-    pub fn data(&self) -> Result<Vec<u8>, AttachmentError> {
-        let mut file = File::open(&self.path)?;
-        let mut buf = Vec::new();
-        let _ = file.read_to_end(&mut buf)?;
-        Ok(buf)
+    // Where the bytes are: the shared blob, or the file itself for an
+    // attachment stored before blobs.
+    pub fn data_path(&self) -> &Path {
+        &self.data_path
+    }
+
+    pub fn size(&self) -> Result<u64, AttachmentError> {
+        let metadata = fs::metadata(&self.data_path)?;
+        Ok(metadata.len())
+    }
+
+    // Only stats the files. `modified` is when the attachment itself was
+    // last saved, not when its blob was first written.
+    pub fn info(&self) -> Result<AttachmentInfo, AttachmentError> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        Ok(AttachmentInfo {
+            file_name: self.path.file_name().unwrap().to_string_lossy().into_owned(),
+            mime_type: self.mime_type().to_string(),
+            size: self.size()?,
+            modified: DateTime::<Utc>::from(modified).to_rfc3339_opts(SecondsFormat::Secs, true)
+        })
+    }
+
+    // Read the file in fixed-size chunks on a separate thread so that
+    // serving an attachment never holds more than a chunk in memory.
+    pub fn stream(&self) -> Result<Body, AttachmentError> {
+        let mut file = File::open(&self.data_path)?;
+        let (mut sender, body) = Body::pair();
+        thread::spawn(move || {
+            loop {
+                let mut buf = vec![0; STREAM_CHUNK_SIZE];
+                let chunk = match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Ok(Chunk::from(buf))
+                    },
+                    Err(err) => Err(err.into())
+                };
+                let failed = chunk.is_err();
+                sender = match sender.send(chunk).wait() {
+                    Ok(sender) => sender,
+                    Err(_) => break
+                };
+                if failed {
+                    break;
+                }
+            }
+        });
+        Ok(body)
     }
 
+    // The type given at upload, or else one inferred from the extension.
     pub fn mime_type(&self) -> Mime {
+        if let Some(ref mime_type) = self.given_mime_type {
+            return mime_type.clone();
+        }
         lazy_static! {
             static ref PNG_RE: Regex = Regex::new(r"^(?i)png$").unwrap();
             static ref JPG_RE: Regex = Regex::new(r"^(?i)jpe?g$").unwrap();
@@ -105,10 +331,20 @@ impl Attachment {
     }
 }
 
+#[derive(Serialize)]
+pub struct AttachmentInfo {
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub modified: String
+}
+
 #[derive(Deserialize)]
 pub struct AttachmentData {
     pub file_name: String,
-    pub encoded_data: String
+    pub encoded_data: String,
+    #[serde(default)]
+    pub mime_type: Option<String>
 }
 
 impl AttachmentData {
@@ -123,10 +359,24 @@ impl AttachmentData {
     }
 
     pub fn is_file_name_valid(&self) -> bool {
-        lazy_static! {
-            static ref FILE_NAME_RE: Regex = Regex::new(r"^.+\.\w+$").unwrap();
-        }
-        FILE_NAME_RE.is_match(&self.file_name)
+        is_valid_file_name(&self.file_name)
+    }
+
+    // A `mime_type` that does not parse is ignored.
+    pub fn given_mime_type(&self) -> Option<Mime> {
+        self.mime_type.as_ref().and_then(|mime_type| parse_mime_type(mime_type))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RenameData {
+    pub new_name: String
+}
+
+impl RenameData {
+    pub fn parse(data: &[u8]) -> Result<RenameData, AttachmentError> {
+        let rename_data = serde_json::from_slice::<RenameData>(data)?;
+        Ok(rename_data)
     }
 }
 
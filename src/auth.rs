@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use bcrypt;
+use hyper::{Method, Request};
+use hyper::header::{Authorization, Basic, Bearer};
+use serde_json;
+use sha2::Sha256;
+use digest::{Input, FixedOutput};
+
+use router::Route;
+
+pub const REALM: &'static str = "biowiki";
+
+// Each role includes the ones before it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Editor,
+    Admin
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    IoError(io::Error),
+    JsonError(serde_json::error::Error)
+}
+
+impl error::Error for AuthError {
+    fn description(&self) -> &str {
+        match self {
+            &AuthError::IoError(ref err) => err.description(),
+            &AuthError::JsonError(ref err) => err.description(),
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &AuthError::IoError(ref err) => write!(f, "AuthError::IoError({})", err),
+            &AuthError::JsonError(ref err) => write!(f, "AuthError::JsonError({})", err),
+        }
+    }
+}
+
+impl From<io::Error> for AuthError {
+    fn from(err: io::Error) -> AuthError {
+        AuthError::IoError(err)
+    }
+}
+
+impl From<serde_json::error::Error> for AuthError {
+    fn from(err: serde_json::error::Error) -> AuthError {
+        AuthError::JsonError(err)
+    }
+}
+
+fn digest(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::default();
+    hasher.process(data);
+    hasher.fixed_result().to_vec()
+}
+
+// Compares digests rather than the values themselves, so neither the
+// position of the first difference nor the length leaks through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    digest(a).iter().zip(digest(b).iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Deserialize)]
+struct User {
+    password_hash: String,
+    roles: Vec<Role>
+}
+
+pub enum Denial {
+    Unauthorized,
+    Forbidden
+}
+
+// The role a request needs; `None` leaves it open even with auth on.
+// Health checks and CORS preflights never carry credentials. Editing
+// sockets are opened with GET but save to the page.
+pub fn required_role(route: &Route, method: &Method) -> Option<Role> {
+    match *route {
+        Route::Health | Route::Ready => None,
+        Route::CollectGarbage | Route::ShowConfig | Route::Reindex { .. } |
+            Route::SetWebReadOnly { .. } => Some(Role::Admin),
+        Route::PageSocket { .. } => Some(Role::Editor),
+        // each operation in the batch is authorized on its own
+        Route::Batch => Some(Role::Reader),
+        _ => match *method {
+            Method::Options => None,
+            Method::Get | Method::Head => Some(Role::Reader),
+            _ => Some(Role::Editor)
+        }
+    }
+}
+
+// Users loaded from a JSON object mapping each username to its bcrypt
+// `password_hash` and `roles`. bcrypt is slow by design, so a digest of
+// each password that has verified once is remembered.
+pub struct Users {
+    users: HashMap<String, User>,
+    verified: Mutex<HashSet<(String, Vec<u8>)>>
+}
+
+impl Users {
+    pub fn open(path: &Path) -> Result<Users, AuthError> {
+        let file = File::open(path)?;
+        let users = serde_json::from_reader(file)?;
+        Ok(Users { users, verified: Mutex::new(HashSet::new()) })
+    }
+
+    fn roles(&self, username: &str, password: &str) -> Option<&[Role]> {
+        let user = match self.users.get(username) {
+            Some(user) => user,
+            None => return None
+        };
+
+        let key = (username.to_string(), digest(password.as_bytes()));
+        if self.verified.lock().unwrap().contains(&key) {
+            return Some(&user.roles);
+        }
+        match bcrypt::verify(password, &user.password_hash) {
+            Ok(true) => {
+                self.verified.lock().unwrap().insert(key);
+                Some(&user.roles)
+            },
+            _ => None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Token {
+    token: String,
+    #[serde(default)]
+    name: Option<String>,
+    roles: Vec<Role>
+}
+
+// Bearer tokens loaded from a JSON array of `token` and `roles` objects,
+// each with an optional `name` recorded as the acting user.
+pub struct Tokens {
+    tokens: Vec<Token>
+}
+
+impl Tokens {
+    pub fn open(path: &Path) -> Result<Tokens, AuthError> {
+        let file = File::open(path)?;
+        let tokens = serde_json::from_reader(file)?;
+        Ok(Tokens { tokens })
+    }
+
+    // Every token is compared, even after a match.
+    fn find(&self, token: &str) -> Option<&Token> {
+        let mut found = None;
+        for candidate in self.tokens.iter() {
+            if constant_time_eq(candidate.token.as_bytes(), token.as_bytes()) {
+                found = Some(candidate);
+            }
+        }
+        found
+    }
+}
+
+// Basic and bearer credentials are checked independently; either or both
+// may be configured.
+pub struct Auth {
+    pub users: Option<Users>,
+    pub tokens: Option<Tokens>
+}
+
+impl Auth {
+    // On success, the name of the user or token, if it has one.
+    pub fn authorize(&self, request: &Request, required: Role) -> Result<Option<String>, Denial> {
+        let basic = request.headers().get::<Authorization<Basic>>();
+        let bearer = request.headers().get::<Authorization<Bearer>>();
+        let found = match (&self.users, basic, &self.tokens, bearer) {
+            (&Some(ref users), Some(&Authorization(ref credentials)), _, _) => {
+                let password = credentials.password.as_ref().map(String::as_str).unwrap_or("");
+                users.roles(&credentials.username, password).
+                    map(|roles| (roles, Some(credentials.username.clone())))
+            },
+            (_, _, &Some(ref tokens), Some(&Authorization(ref credentials))) => {
+                tokens.find(&credentials.token).
+                    map(|token| (&token.roles[..], token.name.clone()))
+            },
+            _ => None
+        };
+        match found {
+            Some((roles, name)) if roles.iter().any(|role| *role >= required) => Ok(name),
+            Some(_) => Err(Denial::Forbidden),
+            None => Err(Denial::Unauthorized)
+        }
+    }
+
+    // WWW-Authenticate challenges for the schemes that are enabled.
+    pub fn challenges(&self) -> Vec<Vec<u8>> {
+        let mut challenges = Vec::new();
+        if self.users.is_some() {
+            challenges.push(format!("Basic realm=\"{}\"", REALM).into_bytes());
+        }
+        if self.tokens.is_some() {
+            challenges.push(format!("Bearer realm=\"{}\"", REALM).into_bytes());
+        }
+        challenges
+    }
+}
@@ -0,0 +1,64 @@
+use hyper::{Headers, Method, Uri};
+use hyper::header::ContentType;
+use hyper::server::Request;
+use serde_json::{self, Value};
+
+pub const MAX_BATCH_SIZE: usize = 50;
+
+// Headers a sub-request takes from its batch, so it is sent as the same
+// client and answered in the same format.
+const FORWARDED_HEADERS: [&'static str; 3] = ["Authorization", "Accept", "X-Forwarded-For"];
+
+// One operation in a batch; `body` is sent as JSON when present.
+#[derive(Deserialize)]
+pub struct BatchItem {
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub body: Option<Value>
+}
+
+#[derive(Serialize)]
+pub struct BatchResult {
+    pub status: u16,
+    pub body: Value
+}
+
+pub fn parse(data: &[u8]) -> Result<Vec<BatchItem>, serde_json::Error> {
+    serde_json::from_slice(data)
+}
+
+pub fn forwarded_headers(request: &Request) -> Headers {
+    let mut headers = Headers::new();
+    for name in FORWARDED_HEADERS.iter() {
+        if let Some(raw) = request.headers().get_raw(name) {
+            headers.set_raw(*name, raw.clone());
+        }
+    }
+    headers
+}
+
+impl BatchItem {
+    pub fn request(&self, headers: &Headers) -> Result<Request, &'static str> {
+        let method = self.method.to_uppercase().parse::<Method>().map_err(|_| "method is not valid")?;
+        if !self.path.starts_with('/') {
+            return Err("path must start with /");
+        }
+        let uri = self.path.parse::<Uri>().map_err(|_| "path is not valid")?;
+        let mut request = Request::new(method, uri);
+        request.headers_mut().extend(headers.iter());
+        if let Some(ref body) = self.body {
+            request.headers_mut().set(ContentType::json());
+            request.set_body(serde_json::to_vec(body).unwrap());
+        }
+        Ok(request)
+    }
+}
+
+// A sub-response body as JSON, or as a string when it is not JSON.
+pub fn response_body(data: &[u8]) -> Value {
+    if data.is_empty() {
+        return Value::Null;
+    }
+    serde_json::from_slice(data).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(data).into_owned()))
+}
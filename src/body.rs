@@ -0,0 +1,134 @@
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::time::Duration;
+use hyper::{self, Body, Chunk, StatusCode};
+use hyper::header::ContentLength;
+use hyper::server::Request;
+use futures::{Future, Sink, Stream, BoxFuture};
+use futures::sync::mpsc::Sender;
+use tokio_timer::{self, Timer, TimeoutError};
+
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+// Long enough for any sensible request timeout; beyond the wheel's
+// natural span timeouts merely fire with less precision.
+const MAX_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+
+lazy_static! {
+    static ref TIMER: Timer = tokio_timer::wheel().
+        max_timeout(Duration::from_secs(MAX_TIMEOUT_SECS)).
+        build();
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    TimedOut,
+    // the body is not as long as the request's Content-Length said
+    LengthMismatch
+}
+
+impl BodyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            &BodyError::TooLarge => StatusCode::PayloadTooLarge,
+            &BodyError::TimedOut => StatusCode::RequestTimeout,
+            &BodyError::LengthMismatch => StatusCode::BadRequest
+        }
+    }
+}
+
+enum ReadError {
+    TooLarge,
+    TimedOut,
+    Hyper(hyper::Error)
+}
+
+impl<T> From<TimeoutError<T>> for ReadError {
+    fn from(_: TimeoutError<T>) -> ReadError {
+        ReadError::TimedOut
+    }
+}
+
+// Collect a request body into memory, giving up as soon as it grows past
+// `limit` bytes or takes longer than `timeout` to arrive, instead of
+// buffering whatever the client sends for as long as it cares to. A
+// body that does not match a declared Content-Length is refused, so a
+// truncated or padded transfer is never written.
+pub fn read_body(request: Request, limit: usize, timeout: Duration) -> BoxFuture<Result<Vec<u8>, BodyError>, hyper::Error> {
+    let declared = request.headers().get::<ContentLength>().map(|length| length.0);
+    let data = request.body().map_err(ReadError::Hyper).fold(Vec::new(), move |mut data, chunk| {
+        if data.len() + chunk.len() > limit {
+            return Err(ReadError::TooLarge);
+        }
+        data.extend_from_slice(&chunk);
+        Ok(data)
+    });
+    TIMER.timeout(data, timeout).then(move |result| {
+        match result {
+            Ok(ref data) if declared.map_or(false, |declared| declared != data.len() as u64) => {
+                Ok(Err(BodyError::LengthMismatch))
+            },
+            Ok(data) => Ok(Ok(data)),
+            Err(ReadError::TooLarge) => Ok(Err(BodyError::TooLarge)),
+            Err(ReadError::TimedOut) => Ok(Err(BodyError::TimedOut)),
+            Err(ReadError::Hyper(err)) => Err(err)
+        }
+    }).boxed()
+}
+
+// Adapts a body sender into a `Write`, turning each write into a chunk.
+// Sending blocks until the client has consumed the previous chunk, so
+// memory stays bounded by the buffer size.
+pub struct ChunkWriter {
+    sender: Option<Sender<Result<Chunk, hyper::Error>>>
+}
+
+impl ChunkWriter {
+    fn send(&mut self, chunk: Result<Chunk, hyper::Error>) -> io::Result<()> {
+        let sender = match self.sender.take() {
+            Some(sender) => sender,
+            None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "body closed"))
+        };
+        match sender.send(chunk).wait() {
+            Ok(sender) => {
+                self.sender = Some(sender);
+                Ok(())
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.send(Ok(Chunk::from(buf.to_vec())))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Run `produce` on its own thread, streaming whatever it writes as the
+// response body. An error aborts the body so the client sees a
+// truncated transfer rather than a silently short one.
+pub fn stream_body<F>(produce: F) -> Body
+    where F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static
+{
+    let (sender, body) = Body::pair();
+    thread::spawn(move || {
+        let mut writer = BufWriter::with_capacity(STREAM_BUFFER_SIZE, ChunkWriter { sender: Some(sender) });
+        let result = produce(&mut writer).and_then(|_| writer.flush());
+        if let Err(err) = result {
+            if let Ok(mut chunk_writer) = writer.into_inner() {
+                let _ = chunk_writer.send(Err(err.into()));
+            }
+        }
+    });
+    body
+}
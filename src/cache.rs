@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use page::PageDetail;
+
+struct CacheEntry {
+    detail: PageDetail,
+    modified: SystemTime,
+    last_used: u64
+}
+
+// Parsed page details keyed by (web name, page name). Each entry remembers
+// the mtime of the page file it was read from so stale entries are
+// dropped instead of served. A capacity of zero disables caching.
+pub struct PageCache {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<(String, String), CacheEntry>
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache { capacity, clock: 0, entries: HashMap::new() }
+    }
+
+    pub fn get(&mut self, web_name: &str, page_name: &str, modified: SystemTime) -> Option<PageDetail> {
+        let key = (web_name.to_string(), page_name.to_string());
+        let fresh = match self.entries.get(&key) {
+            Some(entry) => entry.modified == modified,
+            None => return None
+        };
+        if !fresh {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(&key).unwrap();
+        entry.last_used = self.clock;
+        Some(entry.detail.clone())
+    }
+
+    pub fn insert(&mut self, web_name: &str, page_name: &str, detail: PageDetail, modified: SystemTime) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (web_name.to_string(), page_name.to_string());
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().
+                min_by_key(|&(_, entry)| entry.last_used).
+                map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        let entry = CacheEntry { detail, modified, last_used: self.clock };
+        self.entries.insert(key, entry);
+    }
+
+    pub fn invalidate(&mut self, web_name: &str, page_name: &str) {
+        self.entries.remove(&(web_name.to_string(), page_name.to_string()));
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::{self, Write};
+use hyper::{self, Request, Response, StatusCode};
+use hyper::header::{AcceptEncoding, ContentEncoding, ContentLength, ContentType, Encoding, Vary};
+use unicase::Ascii;
+use futures::{self, Future, Stream, BoxFuture};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use brotli::CompressorWriter;
+use mime;
+
+// bodies smaller than this are not worth the gzip framing overhead
+const MIN_COMPRESS_LENGTH: usize = 860;
+
+// brotli's window size (log2) and internal buffer size
+const BROTLI_LG_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+pub const DEFAULT_BROTLI_QUALITY: u32 = 5;
+pub const MAX_BROTLI_QUALITY: u32 = 11;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Coding {
+    Brotli,
+    Gzip
+}
+
+impl Coding {
+    fn encoding(&self) -> Encoding {
+        match *self {
+            Coding::Brotli => Encoding::Brotli,
+            Coding::Gzip => Encoding::Gzip
+        }
+    }
+
+    fn encode(&self, data: &[u8], brotli_quality: u32) -> Result<Vec<u8>, io::Error> {
+        match *self {
+            Coding::Brotli => {
+                let mut encoder = CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, brotli_quality, BROTLI_LG_WINDOW);
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            },
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+        }
+    }
+}
+
+// Brotli wins over gzip whenever the client accepts both.
+pub fn accepted_coding(request: &Request) -> Option<Coding> {
+    let items = match request.headers().get::<AcceptEncoding>() {
+        Some(&AcceptEncoding(ref items)) => items,
+        None => return None
+    };
+    let accepts = |encoding: Encoding| items.iter().any(|item| {
+        item.item == encoding && item.quality > hyper::header::q(0)
+    });
+    if accepts(Encoding::Brotli) {
+        Some(Coding::Brotli)
+    } else if accepts(Encoding::Gzip) {
+        Some(Coding::Gzip)
+    } else {
+        None
+    }
+}
+
+// Only textual bodies are compressed, since compressing buffers the
+// whole body. Downloads, which are the responses with a
+// Content-Disposition, are streamed as-is whatever their type; that also
+// keeps already-compressed formats (jpeg, png, zip) untouched.
+fn is_compressible(response: &Response) -> bool {
+    // the connection is handed over after a protocol switch
+    if response.headers().has::<ContentEncoding>() || response.status() == StatusCode::SwitchingProtocols {
+        return false;
+    }
+    if response.headers().get_raw("Content-Disposition").is_some() {
+        return false;
+    }
+    match response.headers().get::<ContentType>() {
+        Some(&ContentType(ref mime)) => {
+            match (mime.type_(), mime.subtype()) {
+                // event streams never end, so they cannot be buffered
+                (mime::TEXT, mime::EVENT_STREAM) => false,
+                (mime::TEXT, _) | (mime::APPLICATION, mime::JSON) => true,
+                _ => false
+            }
+        },
+        None => true
+    }
+}
+
+pub fn compress(response: Response, coding: Coding, brotli_quality: u32) -> BoxFuture<Response, hyper::Error> {
+    if !is_compressible(&response) {
+        return futures::future::ok(response).boxed();
+    }
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    response.body().concat2().map(move |body| {
+        let mut response = Response::new().
+            with_status(status).
+            with_headers(headers);
+        if body.len() < MIN_COMPRESS_LENGTH {
+            response.set_body(body.to_vec());
+            return response;
+        }
+
+        match coding.encode(&body, brotli_quality) {
+            Ok(data) => {
+                response.headers_mut().set(ContentEncoding(vec![coding.encoding()]));
+                response.headers_mut().set(ContentLength(data.len() as u64));
+                response.headers_mut().set(Vary::Items(vec![Ascii::new("Accept-Encoding".to_owned())]));
+                response.set_body(data);
+            },
+            Err(_) => response.set_body(body.to_vec())
+        }
+        response
+    }).boxed()
+}
@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use store::Storage;
+use compress::DEFAULT_BROTLI_QUALITY;
+
+pub const DEFAULT_HOST: &'static str = "127.0.0.1";
+pub const DEFAULT_PORT: &'static str = "3000";
+pub const DEFAULT_MAX_CONTENT_LENGTH: usize = 1024 * 1024;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+// shown in place of paths to credentials and private keys
+const REDACTED: &'static str = "[redacted]";
+
+// Attachments can be handed to a front proxy to send. nginx is given a
+// URI under its internal location, which must alias the data directory
+// (or the attachments directory, when there is one); X-Sendfile servers
+// are given the absolute path.
+pub enum FileOffload {
+    AccelRedirect(String),
+    Sendfile
+}
+
+// PEM certificate chain and PKCS#8 PEM private key.
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf
+}
+
+// `listen`, when not empty, replaces `host` and `port`.
+pub struct Config {
+    pub host: String,
+    pub port: String,
+    pub listen: Vec<SocketAddr>,
+    pub path: PathBuf,
+    pub max_content_length: usize,
+    pub max_body_size: usize,
+    pub cache_capacity: usize,
+    pub max_pages_per_web: Option<usize>,
+    pub brotli_quality: u32,
+    pub request_timeout: Duration,
+    pub case_insensitive_pages: bool,
+    pub access_log: Option<PathBuf>,
+    // requests per minute per client address; `None` is unlimited
+    pub rate_limit_read: Option<u32>,
+    pub rate_limit_write: Option<u32>,
+    pub trust_forwarded_for: bool,
+    pub file_offload: Option<FileOffload>,
+    pub auth: Option<PathBuf>,
+    pub tokens: Option<PathBuf>,
+    pub storage: Storage,
+    pub init: bool,
+    pub http2: bool,
+    pub tls: Option<TlsConfig>
+}
+
+impl Config {
+    pub fn new(path: PathBuf) -> Config {
+        Config {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT.to_string(),
+            listen: Vec::new(),
+            path: path,
+            max_content_length: DEFAULT_MAX_CONTENT_LENGTH,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            max_pages_per_web: None,
+            brotli_quality: DEFAULT_BROTLI_QUALITY,
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            case_insensitive_pages: false,
+            access_log: None,
+            rate_limit_read: None,
+            rate_limit_write: None,
+            trust_forwarded_for: false,
+            file_offload: None,
+            auth: None,
+            tokens: None,
+            storage: Storage::new(),
+            init: false,
+            http2: false,
+            tls: None
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TlsSummary {
+    pub cert: String,
+    pub key: &'static str
+}
+
+#[derive(Serialize)]
+pub struct StorageSummary {
+    pub format: &'static str,
+    pub attachments_root: Option<String>,
+    pub durable: bool,
+    pub retry_writes: bool,
+    pub allow_symlinks: bool
+}
+
+// The configuration as an admin may see it. Whether users, tokens and a
+// TLS key are configured is shown, but not where they are kept.
+#[derive(Serialize)]
+pub struct ConfigSummary {
+    pub host: String,
+    pub port: String,
+    pub listen: Vec<String>,
+    pub path: String,
+    pub max_content_length: usize,
+    pub max_body_size: usize,
+    pub cache_capacity: usize,
+    pub max_pages_per_web: Option<usize>,
+    pub brotli_quality: u32,
+    pub request_timeout_secs: u64,
+    pub case_insensitive_pages: bool,
+    pub access_log: Option<String>,
+    pub rate_limit_read: Option<u32>,
+    pub rate_limit_write: Option<u32>,
+    pub trust_forwarded_for: bool,
+    pub accel_redirect: Option<String>,
+    pub sendfile: bool,
+    pub auth: Option<&'static str>,
+    pub tokens: Option<&'static str>,
+    pub storage: StorageSummary,
+    pub init: bool,
+    pub http2: bool,
+    pub tls: Option<TlsSummary>
+}
+
+impl Config {
+    pub fn summary(&self) -> ConfigSummary {
+        let (accel_redirect, sendfile) = match self.file_offload {
+            Some(FileOffload::AccelRedirect(ref prefix)) => (Some(prefix.clone()), false),
+            Some(FileOffload::Sendfile) => (None, true),
+            None => (None, false)
+        };
+        ConfigSummary {
+            host: self.host.clone(),
+            port: self.port.clone(),
+            listen: self.listen.iter().map(|addr| addr.to_string()).collect(),
+            path: self.path.display().to_string(),
+            max_content_length: self.max_content_length,
+            max_body_size: self.max_body_size,
+            cache_capacity: self.cache_capacity,
+            max_pages_per_web: self.max_pages_per_web,
+            brotli_quality: self.brotli_quality,
+            request_timeout_secs: self.request_timeout.as_secs(),
+            case_insensitive_pages: self.case_insensitive_pages,
+            access_log: self.access_log.as_ref().map(|path| path.display().to_string()),
+            rate_limit_read: self.rate_limit_read,
+            rate_limit_write: self.rate_limit_write,
+            trust_forwarded_for: self.trust_forwarded_for,
+            accel_redirect,
+            sendfile,
+            auth: self.auth.as_ref().map(|_| REDACTED),
+            tokens: self.tokens.as_ref().map(|_| REDACTED),
+            storage: StorageSummary {
+                format: self.storage.format.name(),
+                attachments_root: self.storage.attachments_root.as_ref().map(|path| path.display().to_string()),
+                durable: self.storage.durable,
+                retry_writes: self.storage.retry_writes,
+                allow_symlinks: self.storage.allow_symlinks
+            },
+            init: self.init,
+            http2: self.http2,
+            tls: self.tls.as_ref().map(|tls| TlsSummary { cert: tls.cert.display().to_string(), key: REDACTED })
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// Beyond this many table cells the middle of the diff is reported as a
+// plain replacement instead of searching for the longest common run.
+const MAX_TABLE_CELLS: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Delete,
+    Insert
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub line: String
+}
+
+impl DiffLine {
+    fn new(op: DiffOp, line: &str) -> DiffLine {
+        DiffLine { op, line: line.to_string() }
+    }
+}
+
+// Line-based diff of `old` against `new` using a longest common
+// subsequence table, after trimming the lines both sides share at the
+// start and end.
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    let prefix = old.iter().zip(new.iter()).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().
+        zip(new[prefix..].iter().rev()).
+        take_while(|&(a, b)| a == b).
+        count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut lines: Vec<DiffLine> = old[..prefix].iter().map(|line| DiffLine::new(DiffOp::Equal, line)).collect();
+    lines.extend(diff_middle(old_middle, new_middle));
+    lines.extend(old[old.len() - suffix..].iter().map(|line| DiffLine::new(DiffOp::Equal, line)));
+    lines
+}
+
+fn diff_middle(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    let (n, m) = (old.len(), new.len());
+    if (n + 1) * (m + 1) > MAX_TABLE_CELLS {
+        let mut lines: Vec<DiffLine> = old.iter().map(|line| DiffLine::new(DiffOp::Delete, line)).collect();
+        lines.extend(new.iter().map(|line| DiffLine::new(DiffOp::Insert, line)));
+        return lines;
+    }
+
+    // table[i][j] is the length of the LCS of old[i..] and new[j..]
+    let mut table = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i][j] = if old[i] == new[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            lines.push(DiffLine::new(DiffOp::Equal, old[i]));
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            lines.push(DiffLine::new(DiffOp::Delete, old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::new(DiffOp::Insert, new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|line| DiffLine::new(DiffOp::Delete, line)));
+    lines.extend(new[j..].iter().map(|line| DiffLine::new(DiffOp::Insert, line)));
+    lines
+}
@@ -0,0 +1,70 @@
+use futures::sync::mpsc;
+use hyper::{self, Body, Chunk};
+use serde_json;
+
+// events buffered per client before it is considered too slow to keep up
+const SUBSCRIBER_BUFFER: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub enum EventKind {
+    Created,
+    Updated
+}
+
+impl EventKind {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated"
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventData<'a> {
+    web: &'a str,
+    page: &'a str,
+    kind: &'static str,
+    hash: &'a str
+}
+
+struct Subscriber {
+    web_name: String,
+    sender: mpsc::Sender<Result<Chunk, hyper::Error>>
+}
+
+// Fans page changes out to server-sent event streams, one per connected
+// client. A client whose connection has gone away, or whose buffer is
+// full, is dropped on the next publish. Closed connections are also
+// dropped whenever a client subscribes, so webs nobody writes to do not
+// collect them.
+pub struct PageEvents {
+    subscribers: Vec<Subscriber>
+}
+
+impl PageEvents {
+    pub fn new() -> PageEvents {
+        PageEvents { subscribers: Vec::new() }
+    }
+
+    // A `text/event-stream` body that receives every event for the web.
+    pub fn subscribe(&mut self, web_name: &str) -> Body {
+        self.subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        let (mut sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        // an initial comment so clients see the stream open right away
+        let _ = sender.try_send(Ok(Chunk::from(": connected\n\n")));
+        self.subscribers.push(Subscriber { web_name: web_name.to_string(), sender });
+        Body::from(receiver)
+    }
+
+    pub fn publish(&mut self, web_name: &str, page_name: &str, kind: EventKind, hash: &str) {
+        let data = EventData { web: web_name, page: page_name, kind: kind.as_str(), hash };
+        let message = format!("event: {}\ndata: {}\n\n", kind.as_str(), serde_json::to_string(&data).unwrap());
+        self.subscribers.retain_mut(|subscriber| {
+            if subscriber.web_name != web_name {
+                return true;
+            }
+            subscriber.sender.try_send(Ok(Chunk::from(message.clone()))).is_ok()
+        });
+    }
+}
@@ -0,0 +1,208 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Sink, Stream};
+use futures::future::{self, Either};
+use h2::{self, Reason, RecvStream, SendStream};
+use h2::server::{self, SendResponse};
+use http;
+use hyper::{self, Body, Chunk, StatusCode};
+use hyper::server::{Request, Response, Service};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+// Every HTTP/2 connection opens with this, so a client speaking it can
+// be told apart from an HTTP/1 one by its first bytes.
+const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Connection-specific headers that HTTP/2 forbids in a response.
+const CONNECTION_HEADERS: [&'static str; 5] = [
+    "connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"
+];
+
+// Reads just enough of a new connection to tell whether it opens with the
+// HTTP/2 preface. The bytes read are handed back in front of the stream.
+pub struct Sniff<T> {
+    io: Option<T>,
+    buf: Vec<u8>
+}
+
+pub fn sniff<T>(io: T) -> Sniff<T> {
+    Sniff { io: Some(io), buf: Vec::new() }
+}
+
+impl<T: AsyncRead> Future for Sniff<T> {
+    type Item = (bool, Rewind<T>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let wanted = PREFACE.len() - self.buf.len();
+            if wanted == 0 || !PREFACE.starts_with(&self.buf) {
+                break;
+            }
+            let mut chunk = [0u8; 24];
+            let read = self.io.as_mut().unwrap().read(&mut chunk[..wanted]);
+            match read {
+                Ok(0) => break,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err)
+            }
+        }
+        let is_http2 = self.buf == PREFACE;
+        let io = self.io.take().unwrap();
+        let prefix = self.buf.split_off(0);
+        Ok(Async::Ready((is_http2, Rewind { prefix, io })))
+    }
+}
+
+// A stream that replays `prefix` before reading from `io`.
+pub struct Rewind<T> {
+    prefix: Vec<u8>,
+    io: T
+}
+
+impl<T: Read> Read for Rewind<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.io.read(buf);
+        }
+        let n = cmp::min(buf.len(), self.prefix.len());
+        buf[..n].copy_from_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Rewind<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Rewind<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Rewind<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+// Serve an HTTP/2 connection with a hyper service, running each stream
+// as its own task on `handle`.
+pub fn serve_connection<T, S>(io: T, service: S, handle: Handle) -> Box<dyn Future<Item = (), Error = h2::Error>>
+    where T: AsyncRead + AsyncWrite + 'static,
+          S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+          S::Future: 'static
+{
+    Box::new(server::handshake(io).and_then(move |connection| {
+        connection.for_each(move |(request, respond)| {
+            handle.spawn(serve_stream(&service, &handle, request, respond));
+            Ok(())
+        })
+    }))
+}
+
+fn serve_stream<S>(service: &S, handle: &Handle, request: http::Request<RecvStream>, mut respond: SendResponse<Bytes>)
+    -> Box<dyn Future<Item = (), Error = ()>>
+    where S: Service<Request = Request, Response = Response, Error = hyper::Error>,
+          S::Future: 'static
+{
+    let (parts, recv) = request.into_parts();
+    let head = parts.method == http::Method::HEAD;
+    let body = if recv.is_end_stream() {
+        Body::empty()
+    } else {
+        receive_body(recv, handle)
+    };
+    let request = Request::from(http::Request::from_parts(parts, body));
+
+    Box::new(service.call(request).then(move |result| {
+        let response = result.unwrap_or_else(|_| Response::new().with_status(StatusCode::InternalServerError));
+        let end_of_stream = head || response.body_ref().is_none();
+        let (mut parts, body) = http::Response::from(response).into_parts();
+        for name in CONNECTION_HEADERS.iter() {
+            parts.headers.remove(*name);
+        }
+        match respond.send_response(http::Response::from_parts(parts, ()), end_of_stream) {
+            Ok(_) if end_of_stream => Either::A(future::ok(())),
+            Ok(send) => Either::B(SendBody { body, send, pending: Bytes::new() }),
+            Err(_) => Either::A(future::err(()))
+        }
+    }))
+}
+
+// Feed a request body into a hyper `Body`, returning flow-control
+// capacity to the client as each chunk is taken.
+fn receive_body(mut recv: RecvStream, handle: &Handle) -> Body {
+    let (sender, body) = Body::pair();
+    let mut release = recv.release_capacity().clone();
+    let chunks = recv.then(move |result| -> Result<Result<Chunk, hyper::Error>, ()> {
+        match result {
+            Ok(data) => {
+                let _ = release.release_capacity(data.len());
+                Ok(Ok(Chunk::from(data)))
+            },
+            Err(err) => Ok(Err(io::Error::new(io::ErrorKind::Other, err).into()))
+        }
+    });
+    handle.spawn(sender.sink_map_err(|_| ()).send_all(chunks).map(|_| ()));
+    body
+}
+
+// Stream a response body out only as fast as the client's flow-control
+// window allows, so a slow reader does not make us buffer the whole body.
+struct SendBody {
+    body: Body,
+    send: SendStream<Bytes>,
+    pending: Bytes
+}
+
+impl Future for SendBody {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            // the client has given up on the stream
+            match self.send.poll_reset() {
+                Ok(Async::NotReady) => (),
+                _ => return Ok(Async::Ready(()))
+            }
+
+            if !self.pending.is_empty() {
+                self.send.reserve_capacity(self.pending.len());
+                match self.send.poll_capacity() {
+                    Ok(Async::Ready(Some(0))) => (),
+                    Ok(Async::Ready(Some(capacity))) => {
+                        let size = cmp::min(capacity, self.pending.len());
+                        let data = self.pending.split_to(size);
+                        self.send.send_data(data, false).map_err(|_| ())?;
+                    },
+                    Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => return Err(())
+                }
+                continue;
+            }
+
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.pending = Bytes::from(chunk),
+                Ok(Async::Ready(None)) => {
+                    self.send.send_data(Bytes::new(), true).map_err(|_| ())?;
+                    return Ok(Async::Ready(()));
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    self.send.send_reset(Reason::INTERNAL_ERROR);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use hyper::StatusCode;
+
+pub const IDEMPOTENCY_KEY_HEADER: &'static str = "Idempotency-Key";
+pub const DEFAULT_CAPACITY: usize = 1024;
+pub const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+
+// What a key was sent for. The same key sent for another route, page or
+// file is a different request.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencyScope {
+    pub route: &'static str,
+    pub web_name: String,
+    pub page_name: String,
+    pub file_name: String,
+    pub key: String
+}
+
+// `status` is `None` while the request is still being carried out.
+struct Entry {
+    status: Option<StatusCode>,
+    recorded: Instant
+}
+
+pub enum Reservation {
+    Reserved,
+    InProgress,
+    Done(StatusCode)
+}
+
+// Outcomes of requests that carried an `Idempotency-Key`, so a retried
+// request can be answered without repeating its write. Entries expire
+// after `ttl`; when full, the oldest entry is dropped.
+pub struct IdempotencyKeys {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<IdempotencyScope, Entry>
+}
+
+impl IdempotencyKeys {
+    pub fn new(capacity: usize, ttl: Duration) -> IdempotencyKeys {
+        IdempotencyKeys { capacity, ttl, entries: HashMap::new() }
+    }
+
+    // Claim the key for a request about to be carried out, unless one
+    // with the same key already has been or is being.
+    pub fn reserve(&mut self, scope: &IdempotencyScope) -> Reservation {
+        self.expire();
+        match self.entries.get(scope) {
+            Some(&Entry { status: Some(status), .. }) => return Reservation::Done(status),
+            Some(_) => return Reservation::InProgress,
+            None => ()
+        }
+        if self.capacity == 0 {
+            return Reservation::Reserved;
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().
+                min_by_key(|&(_, entry)| entry.recorded).
+                map(|(scope, _)| scope.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(scope.clone(), Entry { status: None, recorded: Instant::now() });
+        Reservation::Reserved
+    }
+
+    // Record the outcome of a reserved request.
+    pub fn complete(&mut self, scope: &IdempotencyScope, status: StatusCode) {
+        if let Some(entry) = self.entries.get_mut(scope) {
+            entry.status = Some(status);
+            entry.recorded = Instant::now();
+        }
+    }
+
+    // Give up a reservation, so the request can be tried again.
+    pub fn release(&mut self, scope: &IdempotencyScope) {
+        self.entries.remove(scope);
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| entry.recorded.elapsed() < ttl);
+    }
+}
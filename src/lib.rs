@@ -9,25 +9,427 @@ extern crate base64;
 extern crate mime;
 extern crate sha2;
 extern crate digest;
+extern crate flate2;
+extern crate unicase;
+extern crate tar;
+extern crate tokio_core;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate pulldown_cmark;
+extern crate ammonia;
+extern crate chrono;
+extern crate tokio_timer;
+extern crate serde_yaml;
+extern crate brotli;
+extern crate bcrypt;
+extern crate uuid;
+extern crate h2;
+extern crate http;
+extern crate bytes;
+extern crate tokio_io;
+extern crate tungstenite;
 
 mod web;
 mod page;
 mod attachment;
 mod router;
+mod compress;
+mod body;
+mod config;
+mod cache;
+mod links;
+mod negotiate;
+mod idempotency;
+mod lock;
+mod access_log;
+mod store;
+mod events;
+mod diff;
+mod auth;
+mod http2;
+mod socket;
+mod ratelimit;
+mod batch;
+#[cfg(test)]
+mod testutil;
 
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::str;
+use std::fs::{self, File};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use hyper::{Method, StatusCode};
-use hyper::header::{AccessControlAllowOrigin, AccessControlAllowMethods, ContentType};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::{Headers, Method, StatusCode};
+use hyper::header::{AccessControlAllowOrigin, AccessControlAllowMethods, Allow, CacheControl, CacheDirective, ContentLength, ContentRange, ContentRangeSpec, RetryAfter, ContentType, IfNoneMatch, IfUnmodifiedSince, Location};
 use hyper::server::{Http, Request, Response, Service};
-use futures::{Future, Stream, BoxFuture};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::TcpListener;
+use native_tls::Identity;
+use tokio_tls::TlsAcceptor;
+use tokio_io::{AsyncRead, AsyncWrite};
+use futures::{Async, Future, Stream, BoxFuture};
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use batch::{BatchItem, BatchResult, MAX_BATCH_SIZE};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use web::*;
 use page::*;
 use attachment::*;
 use router::Route;
+use cache::PageCache;
+use idempotency::{IdempotencyKeys, IdempotencyScope, Reservation, IDEMPOTENCY_KEY_HEADER};
+use lock::{LockError, PageLocks, LOCK_TOKEN_HEADER};
+use access_log::{AccessLog, AccessLogEntry};
+use events::{EventKind, PageEvents};
+use auth::{Auth, Denial, Tokens, Users};
+use socket::{Rooms, Session};
+use ratelimit::RateLimiter;
+use negotiate::{Format, PatchFormat};
 
+pub use config::{Config, FileOffload, TlsConfig};
+pub use store::{Storage, StorageFormat};
+pub use compress::MAX_BROTLI_QUALITY;
+
+#[derive(Serialize)]
+struct ReindexFailure {
+    web: String,
+    page: String,
+    error: String
+}
+
+#[derive(Serialize, Default)]
+struct ReindexReport {
+    pages_loaded: usize,
+    failed: Vec<ReindexFailure>
+}
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str
+}
+
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    root: String,
+    web_count: usize,
+    tls: bool,
+    auth: bool,
+    access_log: bool,
+    case_insensitive_pages: bool
+}
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 500;
+const DEFAULT_VERSION_LIMIT: usize = 100;
+const MAX_VERSION_LIMIT: usize = 1000;
+const MAX_SUGGESTIONS: usize = 5;
+
+#[derive(Serialize)]
+struct ValidationResult {
+    valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>
+}
+
+#[derive(Serialize)]
+struct BulkResult {
+    name: String,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>
+}
+
+#[derive(Serialize)]
+struct HashBody {
+    hash: String
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String
+}
+
+#[derive(Serialize)]
+struct AmbiguousBody {
+    error: &'static str,
+    candidates: Vec<String>
+}
+
+#[derive(Serialize)]
+struct NotFoundBody {
+    error: &'static str,
+    suggestions: Vec<String>
+}
+
+// Indented JSON is asked for with `?pretty=true` or `X-Pretty: true`;
+// responses are compact otherwise.
+fn wants_pretty(request: &Request) -> bool {
+    let header = request.headers().get_raw("X-Pretty").
+        and_then(|raw| raw.one()).
+        map_or(false, |value| value == b"true");
+    header || router::query_params(request).get("pretty").map_or(false, |pretty| pretty == "true")
+}
+
+fn serialize_response<T: Serialize>(value: &T, pretty: bool) -> String {
+    if pretty {
+        serde_json::to_string_pretty(value).unwrap()
+    } else {
+        serde_json::to_string(value).unwrap()
+    }
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::to_string(&ErrorBody { error: message.to_string() }).unwrap()
+}
+
+// A 404 for a missing page, listing existing pages with similar names.
+fn page_not_found_response(web: &Web, page_name: &str) -> Response {
+    let suggestions = web.suggest(page_name, MAX_SUGGESTIONS).unwrap_or_default();
+    let body = NotFoundBody { error: "page not found", suggestions };
+    json_response(serde_json::to_string(&body).unwrap()).with_status(StatusCode::NotFound)
+}
+
+// A 409 listing the versions a hash prefix could mean.
+fn ambiguous_version_response(hashes: &[String]) -> Response {
+    let body = AmbiguousBody { error: "version hash prefix is ambiguous", candidates: hashes.to_vec() };
+    json_response(serde_json::to_string(&body).unwrap()).with_status(StatusCode::Conflict)
+}
+
+// A 500 naming what is wrong with a page on disk, for errors that mean
+// its directory or detail file is not what the server expects.
+fn page_error_response(err: &PageError) -> Response {
+    if let PageError::Symlink = *err {
+        return json_response(error_body("page path is a symbolic link")).with_status(StatusCode::Forbidden);
+    }
+    json_response(error_body(&page_error_message(err))).with_status(StatusCode::InternalServerError)
+}
+
+fn page_error_message(err: &PageError) -> String {
+    match *err {
+        PageError::NameMismatch => "page detail names a different page".to_string(),
+        PageError::NotDirectory => "page path is not a directory".to_string(),
+        PageError::InvalidPath => "page path is not valid".to_string(),
+        PageError::Utf8Error => "page directory name is not valid UTF-8".to_string(),
+        PageError::JsonError(ref err) => format!("corrupt page data: {}", err),
+        PageError::InvalidFormat(ref message) => format!("corrupt page data: {}", message),
+        PageError::IoError(ref err) => format!("unable to read page: {}", err),
+        PageError::Symlink => "page path is a symbolic link".to_string(),
+        ref err => err.to_string()
+    }
+}
+
+// Parse every page of the webs into the cache. Directories with no
+// page file are not pages; they are skipped rather than counted as
+// failures.
+fn warm_cache(webs: &[Web], cache: &Mutex<PageCache>) -> Result<ReindexReport, WebError> {
+    let mut report = ReindexReport::default();
+    for web in webs {
+        for stub in web.list_pages()? {
+            let modified = web.page_modified(&stub.name);
+            match web.get_page(&stub.name) {
+                Ok(page) => {
+                    if let Some(modified) = modified {
+                        cache.lock().unwrap().insert(&web.name, &stub.name, page.detail, modified);
+                    }
+                    report.pages_loaded += 1;
+                },
+                Err(PageError::NotFound) => (),
+                Err(err) => report.failed.push(ReindexFailure {
+                    web: web.name.clone(),
+                    page: stub.name,
+                    error: page_error_message(&err)
+                })
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn attachment_symlink_response() -> Response {
+    json_response(error_body("attachment path is a symbolic link")).with_status(StatusCode::Forbidden)
+}
+
+fn missing_blob_response() -> Response {
+    json_response(error_body("attachment content is missing")).with_status(StatusCode::InternalServerError)
+}
+
+// A 400 explaining why a body would not parse as `T`: there was nothing
+// in it, it was not JSON, or it was JSON without the expected fields.
+fn invalid_body_response<T: DeserializeOwned>(data: &[u8]) -> Response {
+    json_response(error_body(&invalid_body_message::<T>(data))).with_status(StatusCode::BadRequest)
+}
+
+fn invalid_body_message<T: DeserializeOwned>(data: &[u8]) -> String {
+    if data.iter().all(|byte| byte.is_ascii_whitespace()) {
+        "empty request body".to_string()
+    } else {
+        match serde_json::from_slice::<T>(data) {
+            Err(ref err) if err.is_data() => format!("invalid request body: {}", err),
+            Err(err) => format!("malformed JSON in request body: {}", err),
+            Ok(_) => "invalid request body".to_string()
+        }
+    }
+}
+
+fn quota_message(limit: usize) -> String {
+    format!("web has reached its limit of {} pages; every page counts, but not its versions or attachments", limit)
+}
+
+// Build a Content-Disposition value with a quoted ASCII fallback and an
+// RFC 5987 `filename*` carrying the exact UTF-8 name.
+fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let fallback: String = file_name.chars().map(|c| {
+        if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' }
+    }).collect();
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' |
+            b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
+}
+
+// The value of a single-valued header that has no typed hyper header.
+fn raw_header(request: &Request, name: &str) -> Option<String> {
+    request.headers().get_raw(name).
+        and_then(|raw| raw.one()).
+        and_then(|value| str::from_utf8(value).ok()).
+        map(|value| value.to_string())
+}
+
+// The last X-Forwarded-For entry is the one the trusted proxy added;
+// anything before it came from the client and may be made up.
+fn forwarded_client(value: &str) -> Option<IpAddr> {
+    value.rsplit(',').next().and_then(|ip| ip.trim().parse().ok())
+}
+
+fn json_response(body: String) -> Response {
+    lazy_static! {
+        static ref APPLICATION_JSON_UTF_8: mime::Mime = "application/json; charset=utf-8".parse().unwrap();
+    }
+    Response::new().
+        with_header(ContentType(APPLICATION_JSON_UTF_8.clone())).
+        with_header(ContentLength(body.len() as u64)).
+        with_body(body)
+}
+
+fn page_response(detail: &PageDetail, web_name: &str, format: Format, pretty: bool) -> Response {
+    match format {
+        Format::Json => json_response(serialize_response(detail, pretty)),
+        Format::Html => {
+            let html = detail.render_html(web_name);
+            Response::new().
+                with_header(ContentType(mime::TEXT_HTML_UTF_8)).
+                with_header(ContentLength(html.len() as u64)).
+                with_body(html)
+        }
+    }
+}
+
+// The header telling a front proxy which file to send in our place. A
+// file outside `files_root` cannot be named to nginx, so it is streamed.
+fn offload_header(offload: &FileOffload, files_root: &Path, data_path: &Path) -> Option<(&'static str, String)> {
+    match *offload {
+        FileOffload::AccelRedirect(ref prefix) => {
+            let relative = data_path.strip_prefix(files_root).ok()?;
+            let mut uri = prefix.trim_end_matches('/').to_string();
+            for part in relative.iter() {
+                uri.push('/');
+                uri.push_str(&links::encode_path_segment(part.to_str()?));
+            }
+            Some(("X-Accel-Redirect", uri))
+        },
+        FileOffload::Sendfile => {
+            let path = fs::canonicalize(data_path).ok()?;
+            Some(("X-Sendfile", path.to_str()?.to_string()))
+        }
+    }
+}
+
+fn rate_limited_response(retry_after: u64) -> Response {
+    let mut response = json_response(error_body("too many requests")).
+        with_status(StatusCode::TooManyRequests);
+    response.headers_mut().set(RetryAfter::Delay(Duration::from_secs(retry_after)));
+    response
+}
+
+fn denied_response(auth: &Auth, denial: Denial) -> Response {
+    match denial {
+        Denial::Unauthorized => {
+            let mut response = json_response(error_body("authentication required")).
+                with_status(StatusCode::Unauthorized);
+            response.headers_mut().set_raw("WWW-Authenticate", auth.challenges());
+            response
+        },
+        Denial::Forbidden => {
+            json_response(error_body("insufficient role")).with_status(StatusCode::Forbidden)
+        }
+    }
+}
+
+// bytes of a resumable upload received so far
+const UPLOAD_OFFSET_HEADER: &'static str = "Upload-Offset";
+
+const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+
+// Per-request values that handlers carry into their body closures. `user`
+// names the authenticated caller, when auth is enabled.
+struct RequestContext {
+    request_id: String,
+    user: Option<String>
+}
+
+impl RequestContext {
+    fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(String::as_str)
+    }
+}
+
+// Reuse the caller's X-Request-Id when it is printable ASCII of a sane
+// length, so it cannot corrupt log lines; otherwise mint a random UUID.
+fn request_id(request: &Request) -> String {
+    match raw_header(request, REQUEST_ID_HEADER) {
+        Some(ref id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH &&
+            id.bytes().all(|byte| byte.is_ascii_graphic()) => id.clone(),
+        _ => uuid::Uuid::new_v4().to_string()
+    }
+}
+
+// A page editing socket that a request on this connection has been
+// switched to, waiting for hyper to hand the connection back.
+#[derive(Clone)]
+struct SocketUpgrade {
+    web_name: String,
+    page_name: String,
+    user: Option<String>,
+    request_id: String
+}
+
+// `peer` and `upgrade` belong to the one connection the service was
+// made for.
+#[derive(Clone)]
 struct BioWiki {
-    webs: Arc<Mutex<Webs>>
+    webs: Arc<Mutex<Webs>>,
+    cache: Arc<Mutex<PageCache>>,
+    idempotency_keys: Arc<Mutex<IdempotencyKeys>>,
+    locks: Arc<Mutex<PageLocks>>,
+    events: Arc<Mutex<PageEvents>>,
+    rooms: Arc<Mutex<Rooms>>,
+    upgrade: Arc<Mutex<Option<SocketUpgrade>>>,
+    peer: Option<SocketAddr>,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    auth: Option<Arc<Auth>>,
+    access_log: Option<Arc<AccessLog>>,
+    config: Arc<Config>
 }
 
 impl Service for BioWiki {
@@ -37,28 +439,430 @@ impl Service for BioWiki {
     type Future = BoxFuture<Self::Response, Self::Error>;
 
     fn call(&self, request: Request) -> Self::Future {
-        let mut response = Response::new().
-            with_header(AccessControlAllowOrigin::Any);
+        let route = Route::from(&request);
+        // health checks are answered without CORS headers
+        let cors = match route {
+            Route::Health | Route::Ready => false,
+            _ => true
+        };
+        let coding = compress::accepted_coding(&request);
+        let brotli_quality = self.config.brotli_quality;
+        let started = Instant::now();
+        let method = request.method().to_string();
+        let path = request.path().to_string();
+        let access_log = self.access_log.clone();
+        let request_id = request_id(&request);
+        let response_request_id = request_id.clone();
+        let log_request_id = request_id.clone();
+        let response = self.dispatch(route, request, request_id);
+        response.and_then(move |mut response| {
+            if cors {
+                response.headers_mut().set(AccessControlAllowOrigin::Any);
+            }
+            response.headers_mut().set_raw(REQUEST_ID_HEADER, response_request_id);
+            match coding {
+                Some(coding) => compress::compress(response, coding, brotli_quality),
+                None => futures::future::ok(response).boxed()
+            }
+        }).map(move |response| {
+            if let Some(access_log) = access_log {
+                let elapsed = started.elapsed();
+                let duration_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+                let bytes_out = match response.headers().get::<ContentLength>() {
+                    Some(length) => Some(length.0),
+                    None if response.body_ref().is_none() => Some(0),
+                    None => None
+                };
+                let status = response.status().as_u16();
+                access_log.write(&AccessLogEntry::now(log_request_id, method, path, status, duration_ms, bytes_out));
+            }
+            response
+        }).boxed()
+    }
+}
 
+impl BioWiki {
+    // The X-Forwarded-For address is only believed when the server is
+    // configured to sit behind a proxy that sets it.
+    fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        if self.config.trust_forwarded_for {
+            let forwarded = raw_header(request, "X-Forwarded-For").
+                and_then(|value| forwarded_client(&value));
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        self.peer.map(|peer| peer.ip())
+    }
+
+    // Health checks and preflights are never limited. Reads and writes
+    // are told apart by method.
+    fn rate_limit(&self, route: &Route, request: &Request) -> Result<(), u64> {
+        let rate_limiter = match self.rate_limiter {
+            Some(ref rate_limiter) => rate_limiter,
+            None => return Ok(())
+        };
+        let write = match (route, request.method()) {
+            (&Route::Health, _) | (&Route::Ready, _) | (_, &Method::Options) => return Ok(()),
+            (_, &Method::Get) | (_, &Method::Head) => false,
+            _ => true
+        };
+        match self.client_ip(request) {
+            Some(client) => rate_limiter.lock().unwrap().check(client, write),
+            None => Ok(())
+        }
+    }
+
+    // Rate limiting, authorization and the route's handler, without the
+    // headers and logging `call` adds around them.
+    fn dispatch(&self, route: Route, request: Request, request_id: String) -> BoxFuture<Response, hyper::Error> {
+        // limited before authorizing, which is the expensive part
+        match self.rate_limit(&route, &request) {
+            Err(retry_after) => futures::future::ok(rate_limited_response(retry_after)).boxed(),
+            Ok(()) => {
+                let authorized = match (self.auth.as_ref(), auth::required_role(&route, request.method())) {
+                    (Some(auth), Some(role)) => auth.authorize(&request, role).map_err(|denial| (auth, denial)),
+                    _ => Ok(None)
+                };
+                match authorized {
+                    Ok(user) => self.handle(route, request, RequestContext { request_id, user }),
+                    Err((auth, denial)) => futures::future::ok(denied_response(auth, denial)).boxed()
+                }
+            }
+        }
+    }
+
+    // Runs the batch's operations one after another, each dispatched as
+    // if it had been sent on its own. With `stop_on_error`, the first
+    // status of 400 or above ends the batch.
+    fn run_batch(self, mut items: std::vec::IntoIter<BatchItem>, headers: Headers, stop_on_error: bool, request_id: String, mut results: Vec<BatchResult>)
+        -> BoxFuture<Vec<BatchResult>, hyper::Error>
+    {
+        let item = match items.next() {
+            Some(item) => item,
+            None => return futures::future::ok(results).boxed()
+        };
+        let item_request_id = format!("{}.{}", request_id, results.len() + 1);
+        let response = match item.request(&headers) {
+            Ok(request) => match Route::from(&request) {
+                Route::Batch => {
+                    let response = json_response(error_body("batches cannot be nested")).
+                        with_status(StatusCode::BadRequest);
+                    futures::future::ok(response).boxed()
+                },
+                route => self.dispatch(route, request, item_request_id)
+            },
+            Err(message) => {
+                futures::future::ok(json_response(error_body(message)).with_status(StatusCode::BadRequest)).boxed()
+            }
+        };
+        response.and_then(|response| {
+            let status = response.status().as_u16();
+            response.body().concat2().map(move |data| BatchResult { status, body: batch::response_body(&data) })
+        }).and_then(move |result| {
+            let failed = result.status >= 400;
+            results.push(result);
+            if failed && stop_on_error {
+                futures::future::ok(results).boxed()
+            } else {
+                self.run_batch(items, headers, stop_on_error, request_id, results)
+            }
+        }).boxed()
+    }
+
+    fn handle(&self, route: Route, request: Request, context: RequestContext) -> BoxFuture<Response, hyper::Error> {
+        let mut response = Response::new();
+
+        // preflights advertise only the methods routed for the path
         if let &Method::Options = request.method() {
-            let allow_methods = vec!(
-                Method::Get,
-                Method::Post,
-                Method::Put,
-                Method::Delete
-            );
-            response = response.
-                with_header(AccessControlAllowMethods(allow_methods));
+            let allow_methods = Route::allowed_methods(request.path());
+            if allow_methods.is_empty() {
+                response.set_status(StatusCode::NotFound);
+            } else {
+                response = response.
+                    with_header(AccessControlAllowMethods(allow_methods));
+            }
             return futures::future::ok(response).boxed();
         }
 
-        let route = Route::from(&request);
+        if let Some(web_name) = route.web_name() {
+            if self.webs.lock().unwrap().is_symlinked_web(web_name) {
+                response = json_response(error_body("web path is a symbolic link")).
+                    with_status(StatusCode::Forbidden);
+                return futures::future::ok(response).boxed();
+            }
+        }
+
+        if let Some(web_name) = route.written_web() {
+            let webs = self.webs.lock().unwrap();
+            if let Some(web) = webs.get_web(web_name) {
+                match web.metadata() {
+                    Ok(ref meta) if meta.read_only => {
+                        response = json_response(error_body("web is read-only")).
+                            with_status(StatusCode::Forbidden);
+                        return futures::future::ok(response).boxed();
+                    },
+                    Ok(_) => (),
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                        return futures::future::ok(response).boxed();
+                    }
+                }
+            }
+        }
+
+        let pretty = wants_pretty(&request);
         match route {
+            Route::Info => {
+                let webs = self.webs.lock().unwrap();
+                match webs.list_webs(&ListSpec::default()) {
+                    Ok(list) => {
+                        let info = Info {
+                            version: env!("CARGO_PKG_VERSION"),
+                            root: self.config.path.display().to_string(),
+                            web_count: list.total,
+                            tls: self.config.tls.is_some(),
+                            auth: self.auth.is_some(),
+                            access_log: self.config.access_log.is_some(),
+                            case_insensitive_pages: self.config.case_insensitive_pages
+                        };
+                        response = json_response(serialize_response(&info, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::Health => {
+                let status = Status { status: "ok" };
+                let response = json_response(serialize_response(&status, pretty));
+                futures::future::ok(response).boxed()
+            },
+            Route::Ready => {
+                let response =
+                    if self.webs.lock().unwrap().is_ready() {
+                        json_response(serialize_response(&Status { status: "ok" }, pretty))
+                    } else {
+                        json_response(serialize_response(&Status { status: "unavailable" }, pretty)).
+                            with_status(StatusCode::ServiceUnavailable)
+                    };
+                futures::future::ok(response).boxed()
+            },
+            Route::SearchAll => {
+                let params = router::query_params(&request);
+                let query = params.get("q").map_or("", |q| q.trim());
+                if query.is_empty() {
+                    response = json_response(error_body("query must not be empty")).
+                        with_status(StatusCode::BadRequest);
+                    return futures::future::ok(response).boxed();
+                }
+                let limit = match params.get("limit") {
+                    Some(limit) => match limit.parse::<usize>() {
+                        Ok(limit) => limit.min(MAX_SEARCH_LIMIT),
+                        Err(_) => {
+                            response = json_response(error_body("limit must be a number")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    },
+                    None => DEFAULT_SEARCH_LIMIT
+                };
+
+                let webs = self.webs.lock().unwrap();
+                match webs.search(query, limit) {
+                    Ok(hits) => {
+                        response = json_response(serialize_response(&hits, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::Batch => {
+                let headers = batch::forwarded_headers(&request);
+                let stop_on_error = router::query_params(&request).
+                    get("stop_on_error").map_or(false, |value| value == "true");
+                let service = self.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).and_then(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return futures::future::ok(response).boxed();
+                        }
+                    };
+                    let items = match batch::parse(&data) {
+                        Ok(items) => items,
+                        Err(_) => {
+                            response = json_response(error_body("body must be a JSON array of {method, path, body} objects")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    };
+                    if items.len() > MAX_BATCH_SIZE {
+                        let message = format!("a batch holds at most {} operations", MAX_BATCH_SIZE);
+                        response = json_response(error_body(&message)).with_status(StatusCode::BadRequest);
+                        return futures::future::ok(response).boxed();
+                    }
+                    service.run_batch(items.into_iter(), headers, stop_on_error, context.request_id, Vec::new()).
+                        map(move |results| json_response(serialize_response(&results, pretty))).
+                        boxed()
+                }).boxed()
+            },
+            // Walking every page can take a while, so it is done on its own
+            // thread. The web list is taken up front; webs created during
+            // the walk are left out.
+            Route::Reindex { web_name } => {
+                let webs = {
+                    let webs = self.webs.lock().unwrap();
+                    match web_name {
+                        Some(web_name) => match webs.get_web(&web_name) {
+                            Some(web) => vec![web],
+                            None => {
+                                response.set_status(StatusCode::NotFound);
+                                return futures::future::ok(response).boxed();
+                            }
+                        },
+                        None => match webs.list_webs(&ListSpec::default()) {
+                            Ok(list) => list.webs.iter().filter_map(|stub| webs.get_web(&stub.name)).collect(),
+                            Err(_) => {
+                                response.set_status(StatusCode::InternalServerError);
+                                return futures::future::ok(response).boxed();
+                            }
+                        }
+                    }
+                };
+                let cache = self.cache.clone();
+                let (sender, receiver) = oneshot::channel();
+                thread::spawn(move || {
+                    let _ = sender.send(warm_cache(&webs, &cache));
+                });
+                receiver.then(move |report| {
+                    match report {
+                        Ok(Ok(report)) => Ok(json_response(serialize_response(&report, pretty))),
+                        _ => Ok(response.with_status(StatusCode::InternalServerError))
+                    }
+                }).boxed()
+            },
+            Route::ShowConfig => {
+                let response = json_response(serialize_response(&self.config.summary(), pretty));
+                futures::future::ok(response).boxed()
+            },
+            Route::CollectGarbage => {
+                // holding the lock keeps new webs and pages from appearing
+                // mid-scan
+                let webs = self.webs.lock().unwrap();
+                match webs.gc() {
+                    Ok(report) => {
+                        response = json_response(serialize_response(&report, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            // Kept apart from the web's own routes so that a read-only web
+            // can still be unlocked.
+            Route::SetWebReadOnly { web_name } => {
+                if self.webs.lock().unwrap().get_web(&web_name).is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let read_only = match serde_json::from_slice::<ReadOnlyData>(&data) {
+                        Ok(data) => data.read_only,
+                        Err(_) => {
+                            response.set_status(StatusCode::BadRequest);
+                            return response;
+                        }
+                    };
+
+                    let webs = webs.lock().unwrap();
+                    let web = match webs.get_web(&web_name) {
+                        Some(web) => web,
+                        None => {
+                            response.set_status(StatusCode::NotFound);
+                            return response;
+                        }
+                    };
+                    let result = web.metadata().and_then(|mut meta| {
+                        meta.read_only = read_only;
+                        web.write_metadata(&meta)
+                    }).and_then(|_| web.detail());
+                    match result {
+                        Ok(detail) => {
+                            response = json_response(serialize_response(&detail, pretty));
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
             Route::ListWebs => {
+                let params = router::query_params(&request);
+                let mut spec = ListSpec::default();
+                if let Some(sort) = params.get("sort") {
+                    match WebSort::parse(sort) {
+                        Some(sort) => spec.sort = sort,
+                        None => {
+                            response = json_response(error_body("sort must be one of name, created")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    }
+                }
+                match params.get("order").map(|order| order.as_str()) {
+                    Some("asc") | None => (),
+                    Some("desc") => spec.descending = true,
+                    Some(_) => {
+                        response = json_response(error_body("order must be one of asc, desc")).
+                            with_status(StatusCode::BadRequest);
+                        return futures::future::ok(response).boxed();
+                    }
+                }
+                if let Some(offset) = params.get("offset") {
+                    match offset.parse::<usize>() {
+                        Ok(offset) => spec.offset = offset,
+                        Err(_) => {
+                            response = json_response(error_body("offset must be a number")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    }
+                }
+                if let Some(limit) = params.get("limit") {
+                    match limit.parse::<usize>() {
+                        Ok(limit) => spec.limit = Some(limit),
+                        Err(_) => {
+                            response = json_response(error_body("limit must be a number")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    }
+                }
+
                 let webs = self.webs.lock().unwrap();
-                match webs.list_webs() {
+                match webs.list_webs(&spec) {
                     Ok(stubs) => {
-                        response.set_body(serde_json::to_string(&stubs).unwrap());
+                        response = json_response(serialize_response(&stubs, pretty));
                     },
                     Err(_) => {
                         response.set_status(StatusCode::InternalServerError);
@@ -68,20 +872,30 @@ impl Service for BioWiki {
             },
             Route::CreateWeb => {
                 let webs = self.webs.clone();
-                request.body().concat2().map(move |body| {
-                    let data = body.to_vec();
-                    let stub = WebStub::parse(&data);
-                    if stub.is_err() {
-                        response.set_status(StatusCode::BadRequest);
-                        return response;
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let web_data = WebData::parse(&data);
+                    if web_data.is_err() {
+                        return invalid_body_response::<WebData>(&data);
                     }
 
-                    let stub = stub.unwrap();
-                    match webs.lock().unwrap().create_web(&stub.name) {
+                    let web_data = web_data.unwrap();
+                    match webs.lock().unwrap().create_web(&web_data) {
                         Ok(_) => (),
                         Err(WebError::OverwriteError) => {
                             response.set_status(StatusCode::BadRequest);
                         },
+                        Err(WebError::InvalidName) => {
+                            response.set_status(StatusCode::BadRequest);
+                        },
                         Err(_) => {
                             response.set_status(StatusCode::InternalServerError);
                         }
@@ -89,7 +903,49 @@ impl Service for BioWiki {
                     response
                 }).boxed()
             },
-            Route::ListPages { web_name } => {
+            Route::CloneWeb { web_name } => {
+                let webs = self.webs.clone();
+                let with_history = router::query_params(&request).
+                    get("with_history").map_or(true, |value| value != "false");
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let clone_data = match CloneData::parse(&data) {
+                        Ok(clone_data) => clone_data,
+                        Err(_) => return invalid_body_response::<CloneData>(&data)
+                    };
+
+                    match webs.lock().unwrap().clone_web(&web_name, &clone_data.new_name, with_history) {
+                        Ok(web) => {
+                            response = response.with_status(StatusCode::Created).
+                                with_header(Location::new(format!("/webs/{}", links::encode_path_segment(&web.name))));
+                        },
+                        Err(WebError::NotFound) => {
+                            response.set_status(StatusCode::NotFound);
+                        },
+                        Err(WebError::OverwriteError) => {
+                            response = json_response(error_body("a web with that name already exists")).
+                                with_status(StatusCode::Conflict);
+                        },
+                        Err(WebError::InvalidName) => {
+                            response = json_response(error_body("web name is not valid")).
+                                with_status(StatusCode::BadRequest);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
+            Route::ShowWeb { web_name } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -98,9 +954,9 @@ impl Service for BioWiki {
                 }
 
                 let web = web.unwrap();
-                match web.list_pages() {
-                    Ok(stubs) => {
-                        response.set_body(serde_json::to_string(&stubs).unwrap());
+                match web.detail() {
+                    Ok(detail) => {
+                        response = json_response(serialize_response(&detail, pretty));
                     },
                     Err(_) => {
                         response.set_status(StatusCode::InternalServerError);
@@ -108,7 +964,7 @@ impl Service for BioWiki {
                 }
                 futures::future::ok(response).boxed()
             },
-            Route::ShowPage { web_name, page_name } => {
+            Route::WebIndex { web_name } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -117,12 +973,14 @@ impl Service for BioWiki {
                 }
 
                 let web = web.unwrap();
-                match web.get_page(&page_name) {
-                    Ok(page) => {
-                        response.set_body(serde_json::to_string(&page.detail).unwrap());
+                match web.index_page() {
+                    Ok(ref index) if web.page_exists(index) => {
+                        response.set_status(StatusCode::Found);
+                        response.headers_mut().set(Location::new(links::page_url(&web_name, index)));
                     },
-                    Err(PageError::NotFound) => {
-                        response.set_status(StatusCode::NotFound);
+                    Ok(_) => {
+                        response = json_response(error_body("index page not found")).
+                            with_status(StatusCode::NotFound);
                     },
                     Err(_) => {
                         response.set_status(StatusCode::InternalServerError);
@@ -130,7 +988,7 @@ impl Service for BioWiki {
                 }
                 futures::future::ok(response).boxed()
             },
-            Route::CreatePage { web_name } => {
+            Route::ExportWeb { web_name } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -139,20 +997,1277 @@ impl Service for BioWiki {
                 }
 
                 let web = web.unwrap();
-                request.body().concat2().map(move |body| {
-                    let data = body.to_vec();
-                    let page_detail = PageDetail::parse(&data);
-                    if page_detail.is_err() {
-                        response.set_status(StatusCode::BadRequest);
-                        return response;
-                    }
+                let file_name = format!("{}.tar", web.name);
+                let body = body::stream_body(move |out| {
+                    web.export(out).map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                });
+                let mut response = response.
+                    with_header(ContentType("application/x-tar".parse().unwrap())).
+                    with_body(body);
+                response.headers_mut().set_raw("Content-Disposition", content_disposition("attachment", &file_name));
+                futures::future::ok(response).boxed()
+            },
+            Route::StreamPages { web_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let body = body::stream_body(move |out| {
+                    web.write_ndjson(out).map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                });
+                let response = response.
+                    with_header(ContentType("application/x-ndjson".parse().unwrap())).
+                    with_body(body);
+                futures::future::ok(response).boxed()
+            },
+            Route::WebStats { web_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                match web.stats() {
+                    Ok(stats) => {
+                        response = json_response(serialize_response(&stats, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::BrokenLinks { web_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                match web.broken_links() {
+                    Ok(links) => {
+                        response = json_response(serialize_response(&links, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::LinkGraph { web_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                match web.unwrap().link_graph() {
+                    Ok(graph) => {
+                        response = json_response(serialize_response(&graph, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::WebEvents { web_name } => {
+                if self.webs.lock().unwrap().get_web(&web_name).is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let body = self.events.lock().unwrap().subscribe(&web_name);
+                let response = response.
+                    with_header(ContentType(mime::TEXT_EVENT_STREAM)).
+                    with_header(CacheControl(vec![CacheDirective::NoCache])).
+                    with_body(body);
+                futures::future::ok(response).boxed()
+            },
+            // Nothing is read or written, so the web need not exist.
+            Route::PreviewPage { web_name } => {
+                let max_content_length = self.config.max_content_length;
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let preview_data = match PreviewData::parse(&data) {
+                        Ok(preview_data) => preview_data,
+                        Err(_) => return invalid_body_response::<PreviewData>(&data)
+                    };
+                    if let Err(PageError::Validation(message)) = preview_data.validate(max_content_length) {
+                        return json_response(error_body(&message)).with_status(StatusCode::BadRequest);
+                    }
+
+                    let html = render_content(&preview_data.content, &preview_data.content_type, &web_name);
+                    response.
+                        with_header(ContentType(mime::TEXT_HTML_UTF_8)).
+                        with_header(ContentLength(html.len() as u64)).
+                        with_body(html)
+                }).boxed()
+            },
+            Route::ImportWeb { web_name } => {
+                let webs = self.webs.clone();
+                let overwrite = router::query_params(&request).
+                    get("overwrite").map_or(false, |value| value == "true");
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    match webs.lock().unwrap().import_web(&web_name, &data, overwrite) {
+                        Ok(summary) => {
+                            response = json_response(serialize_response(&summary, pretty));
+                        },
+                        Err(WebError::OverwriteError) => {
+                            response.set_status(StatusCode::Conflict);
+                        },
+                        Err(WebError::InvalidName) | Err(WebError::InvalidArchive) => {
+                            response.set_status(StatusCode::BadRequest);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
+            Route::ListPages { web_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let params = router::query_params(&request);
+                let includes = |name: &str| params.get("include").map_or(false, |include| {
+                    include.split(',').any(|field| field == name)
+                });
+                let include_versions = includes("versions");
+                let include_title = includes("title");
+                let include_detail = includes("detail");
+                match web.list_pages() {
+                    Ok(mut stubs) => {
+                        // pages are only opened when a field needs them
+                        if include_versions || include_title || include_detail {
+                            for stub in stubs.iter_mut() {
+                                let page = web.get_page(&stub.name).ok();
+                                if include_versions {
+                                    stub.version_count = page.as_ref().
+                                        and_then(|page| page.version_count().ok());
+                                }
+                                if include_title {
+                                    stub.title = Some(page.as_ref().map(|page| page.detail.title.clone()));
+                                }
+                                if include_detail {
+                                    stub.detail = Some(page.map(|page| page.detail));
+                                }
+                            }
+                        }
+                        response = json_response(serialize_response(&stubs, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ShowPage { web_name, page_name } => {
+                let format = negotiate::negotiate(&request);
+                if format.is_none() {
+                    response.set_status(StatusCode::NotAcceptable);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let format = format.unwrap();
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let modified = web.page_modified(&page_name);
+                if let Some(modified) = modified {
+                    let detail = self.cache.lock().unwrap().get(&web_name, &page_name, modified);
+                    if let Some(detail) = detail {
+                        let response = page_response(&detail, &web_name, format, pretty);
+                        return futures::future::ok(response).boxed();
+                    }
+                }
+
+                match web.get_page(&page_name) {
+                    Ok(page) => {
+                        if let Some(modified) = modified {
+                            self.cache.lock().unwrap().insert(&web_name, &page_name, page.detail.clone(), modified);
+                        }
+                        let response = page_response(&page.detail, &web_name, format, pretty);
+                        futures::future::ok(response).boxed()
+                    },
+                    Err(PageError::NotFound) if self.config.case_insensitive_pages => {
+                        match web.find_page_name(&page_name) {
+                            Ok(canonical) => {
+                                response.set_status(StatusCode::MovedPermanently);
+                                response.headers_mut().set(Location::new(links::page_url(&web_name, &canonical)));
+                            },
+                            Err(PageError::Ambiguous(names)) => {
+                                response = json_response(serialize_response(&names, pretty)).
+                                    with_status(StatusCode::MultipleChoices);
+                            },
+                            Err(_) => {
+                                response = page_not_found_response(&web, &page_name);
+                            }
+                        }
+                        futures::future::ok(response).boxed()
+                    },
+                    Err(PageError::NotFound) => {
+                        futures::future::ok(page_not_found_response(&web, &page_name)).boxed()
+                    },
+                    Err(err) => futures::future::ok(page_error_response(&err)).boxed()
+                }
+            },
+            Route::ShowPageRaw { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                match web.unwrap().get_page(&page_name) {
+                    Ok(page) => {
+                        let content = page.detail.content().to_string();
+                        response = Response::new().
+                            with_header(ContentType(mime::TEXT_PLAIN_UTF_8)).
+                            with_header(ContentLength(content.len() as u64)).
+                            with_body(content);
+                    },
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(err) => {
+                        response = page_error_response(&err);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ExportPage { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                match web.unwrap().get_page(&page_name) {
+                    Ok(page) => {
+                        let html = page.export_html(&web_name, MAX_EXPORT_EMBED_BYTES);
+                        let disposition = content_disposition("attachment", &format!("{}.html", page_name));
+                        response = Response::new().
+                            with_header(ContentType(mime::TEXT_HTML_UTF_8)).
+                            with_header(ContentLength(html.len() as u64)).
+                            with_body(html);
+                        response.headers_mut().set_raw("Content-Disposition", disposition);
+                    },
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(err) => {
+                        response = page_error_response(&err);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::PageExists { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() || !web.unwrap().page_exists(&page_name) {
+                    response.set_status(StatusCode::NotFound);
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::CreatePage { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let create_web = router::query_params(&request).
+                    get("create_web").map_or(false, |value| value == "true");
+                let mut web = webs.get_web(&web_name);
+                if web.is_none() && create_web {
+                    let web_data = WebData { name: web_name.clone(), title: None, description: None };
+                    web = match webs.create_web(&web_data) {
+                        Ok(web) => Some(web),
+                        // created by someone else in the meantime
+                        Err(WebError::OverwriteError) => webs.get_web(&web_name),
+                        Err(WebError::InvalidName) => {
+                            response = json_response(error_body("web name is not valid")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                            return futures::future::ok(response).boxed();
+                        }
+                    };
+                }
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let max_content_length = self.config.max_content_length;
+                let max_pages = self.config.max_pages_per_web;
+                let cache = self.cache.clone();
+                let events = self.events.clone();
+                let if_none_match_any = match request.headers().get::<IfNoneMatch>() {
+                    Some(&IfNoneMatch::Any) => true,
+                    _ => false
+                };
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let page_detail = PageDetail::parse(&data);
+                    if page_detail.is_err() {
+                        return invalid_body_response::<PageDetail>(&data);
+                    }
+
+                    let page_detail = page_detail.unwrap();
+                    if page_name.as_ref().map_or(false, |name| name != &page_detail.name) {
+                        response = json_response(error_body("page name in the URL does not match the body")).
+                            with_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    if let Err(PageError::Validation(message)) = page_detail.validate(max_content_length) {
+                        response = json_response(error_body(&message)).
+                            with_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    if if_none_match_any && web.page_exists(&page_detail.name) {
+                        response.set_status(StatusCode::PreconditionFailed);
+                        return response;
+                    }
+                    // an existing page fails below as a duplicate instead
+                    if !web.page_exists(&page_detail.name) {
+                        match web.check_page_quota(max_pages) {
+                            Ok(_) => (),
+                            Err(PageError::QuotaExceeded(limit)) => {
+                                response = json_response(error_body(&quota_message(limit))).
+                                    with_status(StatusCode::Forbidden);
+                                return response;
+                            },
+                            Err(_) => {
+                                response.set_status(StatusCode::InternalServerError);
+                                return response;
+                            }
+                        }
+                    }
+                    let page = web.new_page(page_detail);
+                    match page.create() {
+                        Ok(hash) => {
+                            cache.lock().unwrap().invalidate(&web.name, &page.detail.name);
+                            events.lock().unwrap().publish(&web.name, &page.detail.name, EventKind::Created, &hash);
+                            page.record_audit(AuditOp::Create, &hash, context.user(), &context.request_id);
+                        },
+                        Err(PageError::OverwriteError) => {
+                            response.set_status(StatusCode::BadRequest);
+                        },
+                        Err(PageError::InvalidName) => {
+                            response.set_status(StatusCode::BadRequest);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
+            // Each item is created independently; one failure does not stop
+            // the rest.
+            Route::BulkCreatePages { web_name } => {
+                if self.webs.lock().unwrap().get_web(&web_name).is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.clone();
+                let max_content_length = self.config.max_content_length;
+                let max_pages = self.config.max_pages_per_web;
+                let cache = self.cache.clone();
+                let events = self.events.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let items = match serde_json::from_slice::<Vec<serde_json::Value>>(&data) {
+                        Ok(items) => items,
+                        Err(_) => {
+                            response = json_response(error_body("body must be a JSON array of pages")).
+                                with_status(StatusCode::BadRequest);
+                            return response;
+                        }
+                    };
+
+                    // hold the lock for the whole batch rather than per page
+                    let webs = webs.lock().unwrap();
+                    let web = match webs.get_web(&web_name) {
+                        Some(web) => web,
+                        None => {
+                            response.set_status(StatusCode::NotFound);
+                            return response;
+                        }
+                    };
+                    let results: Vec<BulkResult> = items.into_iter().map(|item| {
+                        let name = item.get("name").and_then(|name| name.as_str()).unwrap_or("").to_string();
+                        let detail = match serde_json::from_value::<PageDetail>(item) {
+                            Ok(detail) => detail,
+                            Err(err) => {
+                                return BulkResult { name, status: "error", message: Some(err.to_string()) };
+                            }
+                        };
+                        if let Err(PageError::Validation(message)) = detail.validate(max_content_length) {
+                            return BulkResult { name, status: "error", message: Some(message) };
+                        }
+                        if !web.page_exists(&detail.name) {
+                            match web.check_page_quota(max_pages) {
+                                Ok(_) => (),
+                                Err(PageError::QuotaExceeded(limit)) => {
+                                    return BulkResult { name, status: "error", message: Some(quota_message(limit)) };
+                                },
+                                Err(err) => {
+                                    return BulkResult { name, status: "error", message: Some(err.to_string()) };
+                                }
+                            }
+                        }
+                        let page = web.new_page(detail);
+                        match page.create() {
+                            Ok(hash) => {
+                                cache.lock().unwrap().invalidate(&web.name, &name);
+                                events.lock().unwrap().publish(&web.name, &name, EventKind::Created, &hash);
+                                page.record_audit(AuditOp::Create, &hash, context.user(), &context.request_id);
+                                BulkResult { name, status: "created", message: None }
+                            },
+                            Err(PageError::OverwriteError) => {
+                                BulkResult { name, status: "exists", message: None }
+                            },
+                            Err(err) => {
+                                BulkResult { name, status: "error", message: Some(err.to_string()) }
+                            }
+                        }
+                    }).collect();
+                    json_response(serialize_response(&results, pretty))
+                }).boxed()
+            },
+            Route::UpdatePage { web_name, page_name } => {
+                let lock_token = raw_header(&request, LOCK_TOKEN_HEADER);
+                let may_write = self.locks.lock().unwrap().
+                    may_write(&web_name, &page_name, lock_token.as_ref().map(String::as_str));
+                if !may_write {
+                    response.set_status(StatusCode::Locked);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = web.get_page(&page_name);
+                if let Err(PageError::NotFound) = page {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
+                }
+
+                if request.headers().has::<IfUnmodifiedSince>() {
+                    let since = match request.headers().get::<IfUnmodifiedSince>() {
+                        Some(&IfUnmodifiedSince(date)) => SystemTime::from(date),
+                        None => {
+                            response = json_response(error_body("malformed If-Unmodified-Since date")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    };
+                    // HTTP dates only carry whole seconds
+                    let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    if let Some(modified) = web.page_modified(&page_name) {
+                        if seconds(modified) > seconds(since) {
+                            response.set_status(StatusCode::PreconditionFailed);
+                            return futures::future::ok(response).boxed();
+                        }
+                    }
+                }
+
+                let mut page = page.unwrap();
+                let max_content_length = self.config.max_content_length;
+                let cache = self.cache.clone();
+                let events = self.events.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let detail = PageDetail::parse(&data);
+                    if detail.is_err() {
+                        return invalid_body_response::<PageDetail>(&data);
+                    }
+
+                    let detail = detail.unwrap();
+                    if &page_name != &detail.name {
+                        response.set_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    if let Err(PageError::Validation(message)) = detail.validate(max_content_length) {
+                        response = json_response(error_body(&message)).
+                            with_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    page.detail = detail;
+
+                    match page.update() {
+                        Ok(hash) => {
+                            cache.lock().unwrap().invalidate(&web_name, &page_name);
+                            events.lock().unwrap().publish(&web_name, &page_name, EventKind::Updated, &hash);
+                            page.record_audit(AuditOp::Update, &hash, context.user(), &context.request_id);
+                        },
+                        Err(PageError::NotFound) => {
+                            response.set_status(StatusCode::NotFound);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    };
+                    response
+                }).boxed()
+            },
+            Route::PatchPage { web_name, page_name } => {
+                let patch_format = match negotiate::patch_format(&request) {
+                    Some(patch_format) => patch_format,
+                    None => {
+                        response.set_status(StatusCode::UnsupportedMediaType);
+                        return futures::future::ok(response).boxed();
+                    }
+                };
+
+                let lock_token = raw_header(&request, LOCK_TOKEN_HEADER);
+                let may_write = self.locks.lock().unwrap().
+                    may_write(&web_name, &page_name, lock_token.as_ref().map(String::as_str));
+                if !may_write {
+                    response.set_status(StatusCode::Locked);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = web.get_page(&page_name);
+                if let Err(PageError::NotFound) = page {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
+                }
+
+                let mut page = page.unwrap();
+                let max_content_length = self.config.max_content_length;
+                let cache = self.cache.clone();
+                let events = self.events.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let detail = match patch_format {
+                        PatchFormat::Merge => page.detail.merge(&data),
+                        PatchFormat::MergePatch => page.detail.merge_patch(&data)
+                    };
+                    if detail.is_err() {
+                        response.set_status(StatusCode::BadRequest);
+                        return response;
+                    }
+
+                    let detail = detail.unwrap();
+                    if &page_name != &detail.name {
+                        response.set_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    if let Err(PageError::Validation(message)) = detail.validate(max_content_length) {
+                        response = json_response(error_body(&message)).
+                            with_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    page.detail = detail;
+
+                    match page.update() {
+                        Ok(hash) => {
+                            cache.lock().unwrap().invalidate(&web_name, &page_name);
+                            events.lock().unwrap().publish(&web_name, &page_name, EventKind::Updated, &hash);
+                            page.record_audit(AuditOp::Update, &hash, context.user(), &context.request_id);
+                        },
+                        Err(PageError::NotFound) => {
+                            response.set_status(StatusCode::NotFound);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    };
+                    response
+                }).boxed()
+            },
+            // The target web is only known from the body, so its read-only
+            // flag and quota are checked here rather than up front.
+            Route::CopyPage { web_name, page_name } => {
+                let webs = self.webs.clone();
+                let with_history = router::query_params(&request).
+                    get("with_history").map_or(false, |value| value == "true");
+                let max_pages = self.config.max_pages_per_web;
+                let cache = self.cache.clone();
+                let events = self.events.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let copy_data = match CopyData::parse(&data) {
+                        Ok(copy_data) => copy_data,
+                        Err(_) => {
+                            response.set_status(StatusCode::BadRequest);
+                            return response;
+                        }
+                    };
+
+                    let webs = webs.lock().unwrap();
+                    let web = match webs.get_web(&web_name) {
+                        Some(web) => web,
+                        None => {
+                            response.set_status(StatusCode::NotFound);
+                            return response;
+                        }
+                    };
+                    if !web.page_exists(&page_name) {
+                        response.set_status(StatusCode::NotFound);
+                        return response;
+                    }
+                    let target_name = copy_data.target_web.unwrap_or(web_name.clone());
+                    if !is_valid_name(&target_name) {
+                        response = json_response(error_body("target web name is not valid")).
+                            with_status(StatusCode::BadRequest);
+                        return response;
+                    }
+                    let target = match webs.get_web(&target_name) {
+                        Some(target) => target,
+                        None => {
+                            response = json_response(error_body("target web not found")).
+                                with_status(StatusCode::NotFound);
+                            return response;
+                        }
+                    };
+                    let new_name = copy_data.new_name.unwrap_or(page_name.clone());
+                    match target.metadata() {
+                        Ok(ref meta) if meta.read_only => {
+                            response = json_response(error_body("web is read-only")).
+                                with_status(StatusCode::Forbidden);
+                            return response;
+                        },
+                        Ok(_) => (),
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                            return response;
+                        }
+                    }
+                    if target.page_exists(&new_name) {
+                        response.set_status(StatusCode::Conflict);
+                        return response;
+                    }
+                    match target.check_page_quota(max_pages) {
+                        Ok(_) => (),
+                        Err(PageError::QuotaExceeded(limit)) => {
+                            response = json_response(error_body(&quota_message(limit))).
+                                with_status(StatusCode::Forbidden);
+                            return response;
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                            return response;
+                        }
+                    }
+
+                    match web.copy_page(&page_name, &target, &new_name, with_history) {
+                        Ok(page) => {
+                            let hash = page.content_hash().unwrap_or_default();
+                            cache.lock().unwrap().invalidate(&target.name, &new_name);
+                            events.lock().unwrap().publish(&target.name, &new_name, EventKind::Created, &hash);
+                            page.record_audit(AuditOp::Create, &hash, context.user(), &context.request_id);
+                            response = response.with_status(StatusCode::Created).
+                                with_header(Location::new(links::page_url(&target.name, &new_name)));
+                        },
+                        Err(PageError::OverwriteError) => {
+                            response.set_status(StatusCode::Conflict);
+                        },
+                        Err(PageError::InvalidName) => {
+                            response = json_response(error_body("page name is not valid")).
+                                with_status(StatusCode::BadRequest);
+                        },
+                        Err(PageError::NotFound) => {
+                            response.set_status(StatusCode::NotFound);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
+            Route::PageSocket { web_name, page_name } => {
+                let accept = match socket::accept_key(&request) {
+                    Some(accept) => accept,
+                    None => {
+                        response = json_response(error_body("expected an HTTP/1.1 WebSocket upgrade")).
+                            with_status(StatusCode::UpgradeRequired);
+                        response.headers_mut().set_raw("Upgrade", "websocket");
+                        return futures::future::ok(response).boxed();
+                    }
+                };
+
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                match web.unwrap().get_page(&page_name) {
+                    Ok(_) => (),
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                        return futures::future::ok(response).boxed();
+                    },
+                    Err(ref err) => {
+                        return futures::future::ok(page_error_response(err)).boxed();
+                    }
+                }
+
+                *self.upgrade.lock().unwrap() = Some(SocketUpgrade {
+                    web_name,
+                    page_name,
+                    user: context.user.clone(),
+                    request_id: context.request_id.clone()
+                });
+                response.set_status(StatusCode::SwitchingProtocols);
+                response.headers_mut().set_raw("Upgrade", "websocket");
+                response.headers_mut().set_raw("Connection", "Upgrade");
+                response.headers_mut().set_raw("Sec-WebSocket-Accept", accept);
+                futures::future::ok(response).boxed()
+            },
+            // Content is unchanged, so there is no new version or event.
+            Route::TouchPage { web_name, page_name } => {
+                let lock_token = raw_header(&request, LOCK_TOKEN_HEADER);
+                let may_write = self.locks.lock().unwrap().
+                    may_write(&web_name, &page_name, lock_token.as_ref().map(String::as_str));
+                if !may_write {
+                    response.set_status(StatusCode::Locked);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.lock().unwrap();
+                let page = match webs.get_web(&web_name) {
+                    Some(web) => web.get_page(&page_name),
+                    None => Err(PageError::NotFound)
+                };
+                let result = page.and_then(|page| {
+                    page.touch()?;
+                    let hash = page.content_hash()?;
+                    page.record_audit(AuditOp::Touch, &hash, context.user(), &context.request_id);
+                    Ok(())
+                });
+                match result {
+                    Ok(_) => (),
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::RepairPage { web_name, page_name } => {
+                let strategy = match router::query_params(&request).get("strategy") {
+                    None => RepairStrategy::Name,
+                    Some(name) => match RepairStrategy::parse(name) {
+                        Some(strategy) => strategy,
+                        None => {
+                            response = json_response(error_body("strategy must be name or directory")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    }
+                };
+                let lock_token = raw_header(&request, LOCK_TOKEN_HEADER);
+                let may_write = self.locks.lock().unwrap().
+                    may_write(&web_name, &page_name, lock_token.as_ref().map(String::as_str));
+                if !may_write {
+                    response.set_status(StatusCode::Locked);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                match web.repair_page(&page_name, strategy) {
+                    Ok(repair) => {
+                        let mut cache = self.cache.lock().unwrap();
+                        cache.invalidate(&web_name, &repair.from);
+                        cache.invalidate(&web_name, &repair.to);
+                        if let Some(ref hash) = repair.hash {
+                            self.events.lock().unwrap().publish(&web_name, &page_name, EventKind::Updated, hash);
+                            if let Ok(page) = web.get_page(&page_name) {
+                                page.record_audit(AuditOp::Update, hash, context.user(), &context.request_id);
+                            }
+                        }
+                        response = json_response(serialize_response(&repair, pretty));
+                    },
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(PageError::InvalidName) => {
+                        response = json_response(error_body("page detail name is not a valid directory name")).
+                            with_status(StatusCode::BadRequest);
+                    },
+                    Err(PageError::OverwriteError) => {
+                        response = json_response(error_body("a page with the detail's name already exists")).
+                            with_status(StatusCode::Conflict);
+                    },
+                    Err(err) => {
+                        response = page_error_response(&err);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ShowPageLock { web_name, page_name } => {
+                match self.locks.lock().unwrap().get(&web_name, &page_name) {
+                    Some(lock) => {
+                        response = json_response(serialize_response(&lock, pretty));
+                    },
+                    None => {
+                        response.set_status(StatusCode::NotFound);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::LockPage { web_name, page_name } => {
+                let token = raw_header(&request, LOCK_TOKEN_HEADER);
+                if token.is_none() {
+                    response = json_response(error_body("Lock-Token header is required")).
+                        with_status(StatusCode::BadRequest);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() || !web.unwrap().page_exists(&page_name) {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                match self.locks.lock().unwrap().acquire(&web_name, &page_name, &token.unwrap()) {
+                    Ok(lock) => {
+                        response = json_response(serialize_response(&lock, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::Locked);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::UnlockPage { web_name, page_name } => {
+                let token = raw_header(&request, LOCK_TOKEN_HEADER).unwrap_or_default();
+                match self.locks.lock().unwrap().release(&web_name, &page_name, &token) {
+                    Ok(_) => (),
+                    Err(LockError::NotLocked) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(LockError::HeldByOther) => {
+                        response.set_status(StatusCode::Locked);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            // Runs the create/update checks without writing anything.
+            Route::ValidatePage { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                if webs.get_web(&web_name).is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let max_content_length = self.config.max_content_length;
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let errors = match PageDetail::parse(&data) {
+                        Ok(detail) => {
+                            let mut errors = Vec::new();
+                            if &page_name != &detail.name {
+                                errors.push("name does not match the page path".to_string());
+                            }
+                            errors.extend(detail.validation_errors(max_content_length));
+                            errors
+                        },
+                        Err(PageError::JsonError(err)) => vec!(err.to_string()),
+                        Err(_) => vec!("page is not valid JSON".to_string())
+                    };
+                    let result = ValidationResult { valid: errors.is_empty(), errors };
+                    json_response(serialize_response(&result, pretty))
+                }).boxed()
+            },
+            Route::ListAttachments { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = web.get_page(&page_name);
+                if let Err(PageError::NotFound) = page {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
+                }
+
+                let page = page.unwrap();
+                match page.list_attachments() {
+                    Ok(stubs) => {
+                        response = json_response(serialize_response(&stubs, pretty));
+                    },
+                    Err(AttachmentError::Symlink) => {
+                        response = attachment_symlink_response();
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::CreateAttachment { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = web.get_page(&page_name);
+                if let Err(PageError::NotFound) = page {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
+                }
+
+                let idempotency_key = raw_header(&request, IDEMPOTENCY_KEY_HEADER);
+                let page = page.unwrap();
+                let overwrite = router::query_params(&request).
+                    get("overwrite").map_or(false, |value| value == "true");
+                let idempotency_keys = self.idempotency_keys.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let att_data = AttachmentData::parse(&data);
+                    if att_data.is_err() {
+                        return invalid_body_response::<AttachmentData>(&data);
+                    }
+
+                    let att_data = att_data.unwrap();
+                    if !att_data.is_file_name_valid() {
+                        response.set_status(StatusCode::BadRequest);
+                        return response;
+                    }
+
+                    // a retry with a key we have already seen gets the
+                    // recorded outcome instead of writing again
+                    let scope = idempotency_key.map(|key| IdempotencyScope {
+                        route: "create_attachment",
+                        web_name: web_name.clone(),
+                        page_name: page_name.clone(),
+                        file_name: att_data.file_name.clone(),
+                        key
+                    });
+                    if let Some(ref scope) = scope {
+                        match idempotency_keys.lock().unwrap().reserve(scope) {
+                            Reservation::Reserved => (),
+                            Reservation::InProgress => {
+                                return json_response(error_body("a request with this Idempotency-Key is in progress")).
+                                    with_status(StatusCode::Conflict);
+                            },
+                            Reservation::Done(status) => {
+                                response.set_status(status);
+                                return response;
+                            }
+                        }
+                    }
+                    let result = page.save_attachment(att_data, overwrite);
+                    if let Some(ref scope) = scope {
+                        let mut idempotency_keys = idempotency_keys.lock().unwrap();
+                        match result {
+                            Ok(_) => idempotency_keys.complete(scope, response.status()),
+                            Err(_) => idempotency_keys.release(scope)
+                        }
+                    }
+                    match result {
+                        Ok(_) => (),
+                        Err(AttachmentError::Base64Error(_)) => {
+                            response.set_status(StatusCode::BadRequest);
+                        },
+                        Err(AttachmentError::OverwriteError) => {
+                            response = json_response(error_body("attachment already exists; use ?overwrite=true to replace it")).
+                                with_status(StatusCode::Conflict);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
+            Route::UploadAttachment { web_name, page_name, attachment_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = match web.get_page(&page_name) {
+                    Ok(page) => page,
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                        return futures::future::ok(response).boxed();
+                    },
+                    Err(err) => return futures::future::ok(page_error_response(&err)).boxed()
+                };
+                if !is_valid_file_name(&attachment_name) {
+                    response.set_status(StatusCode::BadRequest);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let range = match request.headers().get::<ContentRange>() {
+                    Some(&ContentRange(ContentRangeSpec::Bytes { range: Some((start, end)), instance_length: Some(total) }))
+                        if start <= end && end < total => (start, end, total),
+                    _ => {
+                        response = json_response(error_body("Content-Range: bytes start-end/total is required")).
+                            with_status(StatusCode::BadRequest);
+                        return futures::future::ok(response).boxed();
+                    }
+                };
+                let overwrite = router::query_params(&request).
+                    get("overwrite").map_or(false, |value| value == "true");
+                let webs = self.webs.clone();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let (start, end, total) = range;
+                    if data.len() as u64 != end - start + 1 {
+                        return json_response(error_body("body length does not match Content-Range")).
+                            with_status(StatusCode::BadRequest);
+                    }
+                    // holding the lock makes checking the offset and
+                    // appending one step, and keeps GC from scanning midway
+                    let _webs = webs.lock().unwrap();
+                    match page.append_upload(&attachment_name, start, total, &data, overwrite) {
+                        Ok(true) => {
+                            response.set_status(StatusCode::Created);
+                        },
+                        Ok(false) => {
+                            response.set_status(StatusCode::Accepted);
+                            response.headers_mut().set_raw(UPLOAD_OFFSET_HEADER, (end + 1).to_string());
+                        },
+                        Err(AttachmentError::UnexpectedOffset(received)) => {
+                            let message = format!("{} bytes received; the next chunk must start there", received);
+                            response = json_response(error_body(&message)).with_status(StatusCode::BadRequest);
+                            response.headers_mut().set_raw(UPLOAD_OFFSET_HEADER, received.to_string());
+                        },
+                        Err(AttachmentError::OverwriteError) => {
+                            response = json_response(error_body("attachment already exists; use ?overwrite=true to replace it")).
+                                with_status(StatusCode::Conflict);
+                        },
+                        Err(AttachmentError::TotalMismatch(recorded)) => {
+                            let message = format!("this upload is {} bytes in total", recorded);
+                            response = json_response(error_body(&message)).with_status(StatusCode::BadRequest);
+                        },
+                        Err(_) => {
+                            response.set_status(StatusCode::InternalServerError);
+                        }
+                    }
+                    response
+                }).boxed()
+            },
+            Route::ShowUploadOffset { web_name, page_name, attachment_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let page = match web.unwrap().get_page(&page_name) {
+                    Ok(page) => page,
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                        return futures::future::ok(response).boxed();
+                    },
+                    Err(err) => return futures::future::ok(page_error_response(&err)).boxed()
+                };
+
+                // a finished attachment counts as fully received
+                let received = page.upload_offset(&attachment_name).or_else(|| {
+                    page.get_attachment(&attachment_name).and_then(|att| att.size()).ok()
+                });
+                match received {
+                    Some(received) => {
+                        response.headers_mut().set_raw(UPLOAD_OFFSET_HEADER, received.to_string());
+                    },
+                    None => {
+                        response.set_status(StatusCode::NotFound);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::RenameAttachment { web_name, page_name, attachment_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = web.get_page(&page_name);
+                if let Err(PageError::NotFound) = page {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
+                }
 
-                    let page_detail = page_detail.unwrap();
-                    let page = web.new_page(page_detail);
-                    match page.create() {
-                        Ok(_) => (),
-                        Err(PageError::OverwriteError) => {
+                let page = page.unwrap();
+                let max_body_size = self.config.max_body_size;
+                let request_timeout = self.config.request_timeout;
+                body::read_body(request, max_body_size, request_timeout).map(move |data| {
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            response.set_status(err.status());
+                            return response;
+                        }
+                    };
+                    let rename_data = match RenameData::parse(&data) {
+                        Ok(rename_data) => rename_data,
+                        Err(_) => {
                             response.set_status(StatusCode::BadRequest);
+                            return response;
+                        }
+                    };
+                    match page.rename_attachment(&attachment_name, &rename_data.new_name) {
+                        Ok(_) => (),
+                        Err(AttachmentError::NotFound) => {
+                            response.set_status(StatusCode::NotFound);
+                        },
+                        Err(AttachmentError::InvalidName) => {
+                            response = json_response(error_body("attachment name is not valid")).
+                                with_status(StatusCode::BadRequest);
+                        },
+                        Err(AttachmentError::OverwriteError) => {
+                            response.set_status(StatusCode::Conflict);
                         },
                         Err(_) => {
                             response.set_status(StatusCode::InternalServerError);
@@ -161,7 +2276,7 @@ impl Service for BioWiki {
                     response
                 }).boxed()
             },
-            Route::UpdatePage { web_name, page_name } => {
+            Route::ServeAttachment { web_name, page_name, attachment_name } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -174,40 +2289,63 @@ impl Service for BioWiki {
                 if let Err(PageError::NotFound) = page {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = page {
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
+                }
+
+                let page = page.unwrap();
+                let att = page.get_attachment(&attachment_name);
+                if let Err(AttachmentError::NotFound) = att {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                } else if let Err(AttachmentError::MissingBlob(_)) = att {
+                    return futures::future::ok(missing_blob_response()).boxed();
+                } else if let Err(AttachmentError::Symlink) = att {
+                    return futures::future::ok(attachment_symlink_response()).boxed();
+                } else if let Err(_) = att {
                     response.set_status(StatusCode::InternalServerError);
                     return futures::future::ok(response).boxed();
                 }
 
-                let mut page = page.unwrap();
-                request.body().concat2().map(move |body| {
-                    let data = body.to_vec();
-                    let detail = PageDetail::parse(&data);
-                    if detail.is_err() {
-                        response.set_status(StatusCode::BadRequest);
-                        return response;
-                    }
+                // images render in the browser unless a download is asked
+                // for; SVG can carry scripts, so it never does
+                let att = att.unwrap();
+                let mime_type = att.mime_type();
+                let params = router::query_params(&request);
+                let download = params.get("download").map_or(false, |download| download == "true");
+                let disposition =
+                    if mime_type.type_() == mime::IMAGE && mime_type.subtype() != mime::SVG && !download {
+                        "inline"
+                    } else {
+                        "attachment"
+                    };
+                let disposition = content_disposition(disposition, &attachment_name);
 
-                    let detail = detail.unwrap();
-                    if &page_name != &detail.name {
-                        response.set_status(StatusCode::BadRequest);
-                        return response;
-                    }
-                    page.detail = detail;
+                let files_root = self.config.storage.attachments_root.as_ref().unwrap_or(&self.config.path);
+                let offload = self.config.file_offload.as_ref().
+                    and_then(|offload| offload_header(offload, files_root, att.data_path()));
+                if let Some((name, value)) = offload {
+                    let mut response = response.with_header(ContentType(mime_type));
+                    response.headers_mut().set_raw("Content-Disposition", disposition);
+                    response.headers_mut().set_raw(name, value);
+                    return futures::future::ok(response).boxed();
+                }
 
-                    match page.update() {
-                        Ok(_) => (),
-                        Err(PageError::NotFound) => {
-                            response.set_status(StatusCode::NotFound);
-                        },
-                        Err(_) => {
-                            response.set_status(StatusCode::InternalServerError);
-                        }
-                    };
-                    response
-                }).boxed()
+                let size = att.size();
+                let body = att.stream();
+                if size.is_err() || body.is_err() {
+                    response.set_status(StatusCode::InternalServerError);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let mut response = response.
+                    with_header(ContentType(mime_type)).
+                    with_header(ContentLength(size.unwrap())).
+                    with_body(body.unwrap());
+                response.headers_mut().set_raw("Content-Disposition", disposition);
+                futures::future::ok(response).boxed()
             },
-            Route::ListAttachments { web_name, page_name } => {
+            Route::ShowAttachmentInfo { web_name, page_name, attachment_name } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -220,15 +2358,23 @@ impl Service for BioWiki {
                 if let Err(PageError::NotFound) = page {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = page {
-                    response.set_status(StatusCode::InternalServerError);
-                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
                 }
 
                 let page = page.unwrap();
-                match page.list_attachments() {
-                    Ok(stubs) => {
-                        response.set_body(serde_json::to_string(&stubs).unwrap());
+                match page.get_attachment(&attachment_name).and_then(|att| att.info()) {
+                    Ok(info) => {
+                        response = json_response(serialize_response(&info, pretty));
+                    },
+                    Err(AttachmentError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(AttachmentError::MissingBlob(_)) => {
+                        response = missing_blob_response();
+                    },
+                    Err(AttachmentError::Symlink) => {
+                        response = attachment_symlink_response();
                     },
                     Err(_) => {
                         response.set_status(StatusCode::InternalServerError);
@@ -236,7 +2382,20 @@ impl Service for BioWiki {
                 }
                 futures::future::ok(response).boxed()
             },
-            Route::CreateAttachment { web_name, page_name } => {
+            Route::ListPageVersions { web_name, page_name } => {
+                let params = router::query_params(&request);
+                let limit = match params.get("limit") {
+                    Some(limit) => match limit.parse::<usize>() {
+                        Ok(limit) if limit > 0 => limit.min(MAX_VERSION_LIMIT),
+                        _ => {
+                            response = json_response(error_body("limit must be a positive number")).
+                                with_status(StatusCode::BadRequest);
+                            return futures::future::ok(response).boxed();
+                        }
+                    },
+                    None => DEFAULT_VERSION_LIMIT
+                };
+
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -249,38 +2408,81 @@ impl Service for BioWiki {
                 if let Err(PageError::NotFound) = page {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = page {
-                    response.set_status(StatusCode::InternalServerError);
-                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
                 }
 
                 let page = page.unwrap();
-                request.body().concat2().map(move |body| {
-                    let data = body.to_vec();
-                    let att_data = AttachmentData::parse(&data);
-                    if att_data.is_err() {
-                        response.set_status(StatusCode::BadRequest);
-                        return response;
+                match page.list_versions_page(params.get("before").map(|before| before.as_str()), limit) {
+                    Ok(list) => {
+                        response = json_response(serialize_response(&list, pretty));
+                    },
+                    Err(PageError::NotFound) => {
+                        response = json_response(error_body("before is not a version of this page")).
+                            with_status(StatusCode::BadRequest);
+                    },
+                    Err(ref err @ PageError::Symlink) => {
+                        response = page_error_response(err);
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
                     }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ShowPageHash { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
 
-                    let att_data = att_data.unwrap();
-                    if !att_data.is_file_name_valid() {
-                        response.set_status(StatusCode::BadRequest);
-                        return response;
+                match web.unwrap().get_page(&page_name).and_then(|page| page.content_hash()) {
+                    Ok(hash) => {
+                        response = json_response(serialize_response(&HashBody { hash }, pretty));
+                    },
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
                     }
-                    match page.save_attachment(att_data) {
-                        Ok(_) => (),
-                        Err(AttachmentError::Base64Error(_)) => {
-                            response.set_status(StatusCode::BadRequest);
-                        },
-                        Err(_) => {
-                            response.set_status(StatusCode::InternalServerError);
-                        }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ShowPageLinks { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let web = web.unwrap();
+                let page = match web.get_page(&page_name) {
+                    Ok(page) => page,
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                        return futures::future::ok(response).boxed();
+                    },
+                    Err(err) => return futures::future::ok(page_error_response(&err)).boxed()
+                };
+                let outbound = page.outbound_links().into_iter().map(|target| {
+                    let exists = web.link_exists(&target);
+                    OutboundLink { target, exists }
+                }).collect();
+                match web.backlinks(&page_name) {
+                    Ok(inbound) => {
+                        response = json_response(serialize_response(&PageLinks { outbound, inbound }, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
                     }
-                    response
-                }).boxed()
+                }
+                futures::future::ok(response).boxed()
             },
-            Route::ServeAttachment { web_name, page_name, attachment_name } => {
+            Route::ShowPageAudit { web_name, page_name } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -293,30 +2495,65 @@ impl Service for BioWiki {
                 if let Err(PageError::NotFound) = page {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = page {
-                    response.set_status(StatusCode::InternalServerError);
-                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
                 }
 
-                let page = page.unwrap();
-                let att = page.get_attachment(&attachment_name);
-                if let Err(AttachmentError::NotFound) = att {
+                match page.unwrap().audit_log() {
+                    Ok(entries) => {
+                        response = json_response(serialize_response(&entries, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ListPageAncestors { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                match webs.ancestors(&web_name, &page_name) {
+                    Ok(ancestors) => {
+                        response = json_response(serialize_response(&ancestors, pretty));
+                    },
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::ListPageChildren { web_name, page_name } => {
+                let webs = self.webs.lock().unwrap();
+                let exists = webs.get_web(&web_name).map_or(false, |web| web.page_exists(&page_name));
+                if !exists {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = att {
-                    response.set_status(StatusCode::InternalServerError);
-                    return futures::future::ok(response).boxed();
                 }
 
-                let att = att.unwrap();
-                let mut response = response.with_header(ContentType(att.mime_type()));
-                match att.data() {
-                    Ok(data) => response.set_body(data),
-                    Err(_) => response.set_status(StatusCode::InternalServerError)
+                match webs.children(&web_name, &page_name) {
+                    Ok(children) => {
+                        response = json_response(serialize_response(&children, pretty));
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
                 }
                 futures::future::ok(response).boxed()
             },
-            Route::ListPageVersions { web_name, page_name } => {
+            Route::ShowPageVersion { web_name, page_name, version_hash, html } => {
+                let format = if html {
+                    Some(Format::Html)
+                } else {
+                    negotiate::negotiate(&request)
+                };
+                if format.is_none() {
+                    response.set_status(StatusCode::NotAcceptable);
+                    return futures::future::ok(response).boxed();
+                }
+
+                let format = format.unwrap();
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -329,15 +2566,46 @@ impl Service for BioWiki {
                 if let Err(PageError::NotFound) = page {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = page {
-                    response.set_status(StatusCode::InternalServerError);
-                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
                 }
 
                 let page = page.unwrap();
-                match page.list_versions() {
-                    Ok(stubs) => {
-                        response.set_body(serde_json::to_string(&stubs).unwrap());
+                match page.get_version(&version_hash) {
+                    Ok(detail) => {
+                        response = page_response(&detail, &web_name, format, pretty);
+                    },
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(PageError::Ambiguous(hashes)) => {
+                        response = ambiguous_version_response(&hashes);
+                    },
+                    Err(_) => {
+                        response.set_status(StatusCode::InternalServerError);
+                    }
+                }
+                futures::future::ok(response).boxed()
+            },
+            Route::DeletePageVersion { web_name, page_name, version_hash } => {
+                let webs = self.webs.lock().unwrap();
+                let web = webs.get_web(&web_name);
+                if web.is_none() {
+                    response.set_status(StatusCode::NotFound);
+                    return futures::future::ok(response).boxed();
+                }
+
+                match web.unwrap().get_page(&page_name).and_then(|page| page.delete_version(&version_hash)) {
+                    Ok(_) => (),
+                    Err(PageError::NotFound) => {
+                        response.set_status(StatusCode::NotFound);
+                    },
+                    Err(PageError::Ambiguous(hashes)) => {
+                        response = ambiguous_version_response(&hashes);
+                    },
+                    Err(PageError::CurrentVersion) => {
+                        response = json_response(error_body("cannot delete the current version")).
+                            with_status(StatusCode::Conflict);
                     },
                     Err(_) => {
                         response.set_status(StatusCode::InternalServerError);
@@ -345,7 +2613,7 @@ impl Service for BioWiki {
                 }
                 futures::future::ok(response).boxed()
             },
-            Route::ShowPageVersion { web_name, page_name, version_hash } => {
+            Route::DiffPageVersion { web_name, page_name, version_hash } => {
                 let webs = self.webs.lock().unwrap();
                 let web = webs.get_web(&web_name);
                 if web.is_none() {
@@ -358,25 +2626,32 @@ impl Service for BioWiki {
                 if let Err(PageError::NotFound) = page {
                     response.set_status(StatusCode::NotFound);
                     return futures::future::ok(response).boxed();
-                } else if let Err(_) = page {
-                    response.set_status(StatusCode::InternalServerError);
-                    return futures::future::ok(response).boxed();
+                } else if let Err(ref err) = page {
+                    return futures::future::ok(page_error_response(err)).boxed();
                 }
 
                 let page = page.unwrap();
-                match page.get_version(&version_hash) {
-                    Ok(detail) => {
-                        response.set_body(serde_json::to_string(&detail).unwrap());
+                match page.diff_versions(&version_hash) {
+                    Ok(diff) => {
+                        response = json_response(serialize_response(&diff, pretty));
                     },
                     Err(PageError::NotFound) => {
                         response.set_status(StatusCode::NotFound);
                     },
+                    Err(PageError::Ambiguous(hashes)) => {
+                        response = ambiguous_version_response(&hashes);
+                    },
                     Err(_) => {
                         response.set_status(StatusCode::InternalServerError);
                     }
                 }
                 futures::future::ok(response).boxed()
             },
+            Route::MethodNotAllowed { allowed } => {
+                response.set_status(StatusCode::MethodNotAllowed);
+                response.headers_mut().set(Allow(allowed));
+                futures::future::ok(response).boxed()
+            },
             Route::Invalid => {
                 response.set_status(StatusCode::NotFound);
                 futures::future::ok(response).boxed()
@@ -385,12 +2660,349 @@ impl Service for BioWiki {
     }
 }
 
-pub fn run(host: String, port: String, path: PathBuf) {
-    let addr = format!("{}:{}", host, port).parse().unwrap();
-    let webs = Arc::new(Mutex::new(Webs { path: path }));
-    let server =
-        Http::new().bind(&addr, move || {
-            Ok(BioWiki { webs: webs.clone() })
-        }).unwrap();
-    server.run().unwrap();
+impl BioWiki {
+    // Join the page's editing room on a connection that hyper has handed
+    // back after switching protocols, leaving it when the socket closes.
+    fn page_socket<T>(&self, io: T, read_buf: Vec<u8>, upgrade: SocketUpgrade, handle: Handle)
+        -> Box<dyn Future<Item = (), Error = ()>>
+        where T: Read + Write + 'static
+    {
+        let stored = match self.load_page(&upgrade.web_name, &upgrade.page_name) {
+            Ok(page) => page.detail,
+            Err(_) => return Box::new(futures::future::ok(()))
+        };
+        let (id, receiver) = self.rooms.lock().unwrap().join(&upgrade.web_name, &upgrade.page_name, stored);
+
+        let service = self.clone();
+        let editing = upgrade.clone();
+        let on_text = move |text: String| service.edit_page_live(&editing, id, &text, &handle);
+        let service = self.clone();
+        Box::new(Session::new(io, read_buf, receiver, on_text).then(move |_| {
+            let draft = service.rooms.lock().unwrap().leave(&upgrade.web_name, &upgrade.page_name, id);
+            if let Some(draft) = draft {
+                service.save_draft(&upgrade, draft);
+            }
+            Ok(())
+        }))
+    }
+
+    fn load_page(&self, web_name: &str, page_name: &str) -> Result<Page, PageError> {
+        let webs = self.webs.lock().unwrap();
+        match webs.get_web(web_name) {
+            Some(web) => web.get_page(page_name),
+            None => Err(PageError::NotFound)
+        }
+    }
+
+    // Apply a client's merge patch to the room's draft, or to the stored
+    // page when there is none, and schedule a save for once edits stop.
+    fn edit_page_live(&self, upgrade: &SocketUpgrade, id: u64, text: &str, handle: &Handle) {
+        let (web_name, page_name) = (&upgrade.web_name, &upgrade.page_name);
+        let result = self.patch_draft(web_name, page_name, text);
+        let generation = {
+            let mut rooms = self.rooms.lock().unwrap();
+            match result {
+                Ok((draft, patch)) => rooms.edit(web_name, page_name, id, draft, &patch),
+                Err(message) => {
+                    rooms.error(web_name, page_name, Some(id), message);
+                    None
+                }
+            }
+        };
+        let generation = match generation {
+            Some(generation) => generation,
+            None => return
+        };
+
+        let service = self.clone();
+        let upgrade = upgrade.clone();
+        let save = move || {
+            let draft = service.rooms.lock().unwrap().take_draft(&upgrade.web_name, &upgrade.page_name, generation);
+            if let Some(draft) = draft {
+                service.save_draft(&upgrade, draft);
+            }
+        };
+        match Timeout::new(Duration::from_millis(socket::SAVE_DELAY_MS), handle) {
+            Ok(timeout) => handle.spawn(timeout.then(move |_| {
+                save();
+                Ok(())
+            })),
+            Err(_) => save()
+        }
+    }
+
+    fn patch_draft(&self, web_name: &str, page_name: &str, text: &str) -> Result<(PageDetail, serde_json::Value), &'static str> {
+        let patch = serde_json::from_str::<serde_json::Value>(text).map_err(|_| "malformed JSON")?;
+        if !self.locks.lock().unwrap().may_write(web_name, page_name, None) {
+            return Err("page is locked");
+        }
+        let draft = self.rooms.lock().unwrap().draft(web_name, page_name);
+        let base = match draft {
+            Some(draft) => draft,
+            None => self.load_page(web_name, page_name).map_err(|_| "unable to read the page")?.detail
+        };
+        let draft = base.merge_patch(text.as_bytes()).map_err(|_| "patch does not produce a valid page")?;
+        if draft.name != page_name {
+            return Err("the page name cannot be changed");
+        }
+        if draft.validate(self.config.max_content_length).is_err() {
+            return Err("patch does not produce a valid page");
+        }
+        Ok((draft, patch))
+    }
+
+    fn save_draft(&self, upgrade: &SocketUpgrade, draft: PageDetail) {
+        let (web_name, page_name) = (&upgrade.web_name, &upgrade.page_name);
+        let result = if self.locks.lock().unwrap().may_write(web_name, page_name, None) {
+            self.load_page(web_name, page_name).and_then(|mut page| {
+                page.detail = draft;
+                page.update().map(|hash| (page, hash))
+            }).map_err(|_| "unable to save the page")
+        } else {
+            Err("page is locked; edits were not saved")
+        };
+        match result {
+            Ok((page, hash)) => {
+                self.cache.lock().unwrap().invalidate(web_name, page_name);
+                self.events.lock().unwrap().publish(web_name, page_name, EventKind::Updated, &hash);
+                page.record_audit(AuditOp::Update, &hash, upgrade.user.as_ref().map(String::as_str), &upgrade.request_id);
+                self.rooms.lock().unwrap().saved(web_name, page_name, &hash);
+            },
+            Err(message) => {
+                self.rooms.lock().unwrap().error(web_name, page_name, None, message);
+            }
+        }
+    }
+}
+
+fn read_file(path: &PathBuf) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn tls_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let cert = read_file(&tls.cert).map_err(|err| format!("{}: {}", tls.cert.display(), err))?;
+    let key = read_file(&tls.key).map_err(|err| format!("{}: {}", tls.key.display(), err))?;
+    let identity = Identity::from_pkcs8(&cert, &key).map_err(|err| err.to_string())?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(|err| err.to_string())?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+// Accept TCP connections ourselves and hand each one to hyper, once the
+// TLS handshake has completed if there is an acceptor. Every address is
+// bound before any is served, so one failing stops startup.
+fn serve<F>(addrs: &[SocketAddr], acceptor: Option<TlsAcceptor>, http2: bool, new_service: F)
+    where F: Fn() -> BioWiki + 'static
+{
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        match TcpListener::bind(addr, &handle) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                println!("unable to listen on {}: {}", addr, err);
+                return;
+            }
+        }
+    }
+    let http = Http::<hyper::Chunk>::new();
+    let (http, acceptor, new_service, handle) = (&http, &acceptor, &new_service, &handle);
+    let servers = listeners.into_iter().map(|listener| {
+        listener.incoming().for_each(move |(socket, peer)| {
+            let mut service = new_service();
+            service.peer = Some(peer);
+            let http = http.clone();
+            let connection_handle = handle.clone();
+            let connection: Box<dyn Future<Item = (), Error = ()>> = match *acceptor {
+                Some(ref acceptor) => Box::new(acceptor.accept(socket).
+                    map_err(|err| println!("TLS handshake failed: {}", err)).
+                    and_then(move |stream| serve_connection(stream, http, http2, service, connection_handle))),
+                None => serve_connection(socket, http, http2, service, connection_handle)
+            };
+            handle.spawn(connection);
+            Ok(())
+        })
+    });
+    core.run(future::join_all(servers)).unwrap();
+}
+
+// With `http2`, a connection that opens with the HTTP/2 preface is served
+// by h2 and anything else by hyper as before. There is no ALPN, since the
+// TLS acceptor cannot offer it, so clients must use prior knowledge.
+fn serve_connection<T>(stream: T, http: Http<hyper::Chunk>, http2: bool, service: BioWiki, handle: Handle)
+    -> Box<dyn Future<Item = (), Error = ()>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    if !http2 {
+        return serve_http1(stream, http, service, handle);
+    }
+    Box::new(http2::sniff(stream).
+        map_err(|err| println!("connection error: {}", err)).
+        and_then(move |(is_http2, stream)| {
+            if is_http2 {
+                Either::A(http2::serve_connection(stream, service, handle).
+                    map_err(|err| println!("HTTP/2 connection error: {}", err)))
+            } else {
+                Either::B(serve_http1(stream, http, service, handle))
+            }
+        }))
+}
+
+// Serve HTTP/1 until the connection closes, or until a request switches
+// it to a page editing socket and hyper hands it back.
+fn serve_http1<T>(stream: T, http: Http<hyper::Chunk>, service: BioWiki, handle: Handle)
+    -> Box<dyn Future<Item = (), Error = ()>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let mut connection = Some(http.serve_connection(stream, service));
+    let served = futures::future::poll_fn(move || {
+        match connection.as_mut().unwrap().poll_without_shutdown() {
+            Ok(Async::Ready(())) => Ok(Async::Ready(connection.take().unwrap().into_parts())),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => Err(err)
+        }
+    });
+    Box::new(served.
+        map_err(|err| println!("connection error: {}", err)).
+        and_then(move |parts| {
+            let upgrade = parts.service.upgrade.lock().unwrap().take();
+            match upgrade {
+                Some(upgrade) => Either::A(parts.service.page_socket(parts.io, parts.read_buf.to_vec(), upgrade, handle)),
+                None => Either::B(tokio_io::io::shutdown(parts.io).map(|_| ()).map_err(|_| ()))
+            }
+        }))
+}
+
+pub fn run(config: Config) {
+    let addrs = if config.listen.is_empty() {
+        vec![format!("{}:{}", config.host, config.port).parse().unwrap()]
+    } else {
+        config.listen.clone()
+    };
+    let webs = Webs { path: config.path.clone(), storage: config.storage.clone() };
+    if config.init {
+        if let Err(err) = webs.scaffold() {
+            println!("unable to initialize {}: {}", config.path.display(), err);
+            return;
+        }
+    }
+    let webs = Arc::new(Mutex::new(webs));
+    let acceptor = match config.tls {
+        Some(ref tls) => match tls_acceptor(tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                println!("unable to set up TLS: {}", err);
+                return;
+            }
+        },
+        None => None
+    };
+    let cache = Arc::new(Mutex::new(PageCache::new(config.cache_capacity)));
+    let idempotency_keys = Arc::new(Mutex::new(IdempotencyKeys::new(
+        idempotency::DEFAULT_CAPACITY,
+        Duration::from_secs(idempotency::DEFAULT_TTL_SECS)
+    )));
+    let locks = Arc::new(Mutex::new(PageLocks::new(Duration::from_secs(lock::DEFAULT_LOCK_TTL_SECS))));
+    let events = Arc::new(Mutex::new(PageEvents::new()));
+    let rooms = Arc::new(Mutex::new(Rooms::new()));
+    let users = match config.auth {
+        Some(ref path) => match Users::open(path) {
+            Ok(users) => Some(users),
+            Err(err) => {
+                println!("unable to load users from {}: {}", path.display(), err);
+                return;
+            }
+        },
+        None => None
+    };
+    let tokens = match config.tokens {
+        Some(ref path) => match Tokens::open(path) {
+            Ok(tokens) => Some(tokens),
+            Err(err) => {
+                println!("unable to load tokens from {}: {}", path.display(), err);
+                return;
+            }
+        },
+        None => None
+    };
+    let auth = if users.is_some() || tokens.is_some() {
+        Some(Arc::new(Auth { users, tokens }))
+    } else {
+        None
+    };
+    let rate_limiter = if config.rate_limit_read.is_some() || config.rate_limit_write.is_some() {
+        Some(Arc::new(Mutex::new(RateLimiter::new(
+            config.rate_limit_read,
+            config.rate_limit_write,
+            ratelimit::DEFAULT_CAPACITY
+        ))))
+    } else {
+        None
+    };
+    let access_log = match config.access_log {
+        Some(ref path) => match AccessLog::open(path) {
+            Ok(access_log) => Some(Arc::new(access_log)),
+            Err(err) => {
+                println!("unable to open access log {}: {}", path.display(), err);
+                return;
+            }
+        },
+        None => None
+    };
+    let http2 = config.http2;
+    let config = Arc::new(config);
+    let new_service = move || {
+        BioWiki {
+            webs: webs.clone(),
+            cache: cache.clone(),
+            idempotency_keys: idempotency_keys.clone(),
+            locks: locks.clone(),
+            events: events.clone(),
+            rooms: rooms.clone(),
+            upgrade: Arc::new(Mutex::new(None)),
+            peer: None,
+            rate_limiter: rate_limiter.clone(),
+            auth: auth.clone(),
+            access_log: access_log.clone(),
+            config: config.clone()
+        }
+    };
+
+    serve(&addrs, acceptor, http2, new_service)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_bodies_are_reported_as_empty() {
+        assert_eq!(invalid_body_message::<WebData>(b""), "empty request body");
+        assert_eq!(invalid_body_message::<WebData>(b"  \n"), "empty request body");
+    }
+
+    #[test]
+    fn malformed_bodies_are_reported_as_malformed() {
+        let message = invalid_body_message::<WebData>(b"{\"name\": ");
+        assert!(message.starts_with("malformed JSON in request body: "), "{}", message);
+    }
+
+    #[test]
+    fn bodies_missing_fields_are_reported_as_invalid() {
+        let message = invalid_body_message::<WebData>(b"{\"title\": \"Main\"}");
+        assert!(message.starts_with("invalid request body: "), "{}", message);
+        assert!(message.contains("name"), "{}", message);
+    }
+
+    #[test]
+    fn spoofed_first_hops_share_one_bucket() {
+        let mut rate_limiter = RateLimiter::new(Some(1), None, ratelimit::DEFAULT_CAPACITY);
+        let first = forwarded_client("10.0.0.1, 192.0.2.7").unwrap();
+        let second = forwarded_client("10.0.0.2,192.0.2.7").unwrap();
+        assert_eq!(first, second);
+        assert!(rate_limiter.check(first, false).is_ok());
+        assert!(rate_limiter.check(second, false).is_err());
+    }
 }
@@ -0,0 +1,44 @@
+use regex::{Captures, Regex};
+
+lazy_static! {
+    static ref WIKI_LINK_RE: Regex = Regex::new(r"\[\[([^\[\]\n]+)\]\]").unwrap();
+}
+
+// Percent-encode everything except RFC 3986 unreserved characters.
+pub fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    encoded
+}
+
+pub fn page_url(web_name: &str, page_name: &str) -> String {
+    format!("/webs/{}/pages/{}", encode_path_segment(web_name), encode_path_segment(page_name))
+}
+
+// The target of every `[[PageName]]` reference, with the 1-based line it
+// appears on.
+pub fn wiki_links(content: &str) -> Vec<(usize, String)> {
+    let mut links = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        for caps in WIKI_LINK_RE.captures_iter(line) {
+            links.push((index + 1, caps[1].trim().to_string()));
+        }
+    }
+    links
+}
+
+// Replace `[[PageName]]` references with Markdown links to the page in
+// the given web.
+pub fn rewrite_wiki_links(content: &str, web_name: &str) -> String {
+    WIKI_LINK_RE.replace_all(content, |caps: &Captures| {
+        let target = caps[1].trim();
+        let label = target.replace('[', "\\[").replace(']', "\\]");
+        format!("[{}]({})", label, page_url(web_name, target))
+    }).into_owned()
+}
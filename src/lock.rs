@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const LOCK_TOKEN_HEADER: &'static str = "Lock-Token";
+pub const DEFAULT_LOCK_TTL_SECS: u64 = 15 * 60;
+
+#[derive(Debug)]
+pub enum LockError {
+    NotLocked,
+    HeldByOther
+}
+
+// Times are seconds since the Unix epoch. The holder's token is left
+// out, since presenting it is what lets a client write the page.
+#[derive(Clone, Serialize)]
+pub struct PageLock {
+    pub acquired: u64,
+    pub expires: u64
+}
+
+struct Entry {
+    holder: String,
+    acquired: SystemTime
+}
+
+// Advisory edit locks keyed by (web name, page name). A lock lapses
+// `ttl` after it was last acquired or refreshed by its holder.
+pub struct PageLocks {
+    ttl: Duration,
+    entries: HashMap<(String, String), Entry>
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+impl PageLocks {
+    pub fn new(ttl: Duration) -> PageLocks {
+        PageLocks { ttl, entries: HashMap::new() }
+    }
+
+    pub fn get(&mut self, web_name: &str, page_name: &str) -> Option<PageLock> {
+        self.expire();
+        let ttl = self.ttl;
+        self.entries.get(&(web_name.to_string(), page_name.to_string())).map(|entry| {
+            PageLock {
+                acquired: epoch_secs(entry.acquired),
+                expires: epoch_secs(entry.acquired + ttl)
+            }
+        })
+    }
+
+    // Take the lock, or refresh it when `holder` already has it.
+    pub fn acquire(&mut self, web_name: &str, page_name: &str, holder: &str) -> Result<PageLock, LockError> {
+        if !self.may_write(web_name, page_name, Some(holder)) {
+            return Err(LockError::HeldByOther);
+        }
+        let entry = Entry { holder: holder.to_string(), acquired: SystemTime::now() };
+        self.entries.insert((web_name.to_string(), page_name.to_string()), entry);
+        Ok(self.get(web_name, page_name).unwrap())
+    }
+
+    pub fn release(&mut self, web_name: &str, page_name: &str, holder: &str) -> Result<(), LockError> {
+        self.expire();
+        let key = (web_name.to_string(), page_name.to_string());
+        match self.entries.get(&key) {
+            Some(entry) if entry.holder != holder => return Err(LockError::HeldByOther),
+            Some(_) => (),
+            None => return Err(LockError::NotLocked)
+        }
+        self.entries.remove(&key);
+        Ok(())
+    }
+
+    // Whether a writer presenting `holder` may change the page.
+    pub fn may_write(&mut self, web_name: &str, page_name: &str, holder: Option<&str>) -> bool {
+        self.expire();
+        match self.entries.get(&(web_name.to_string(), page_name.to_string())) {
+            Some(entry) => holder == Some(entry.holder.as_str()),
+            None => true
+        }
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| {
+            entry.acquired.elapsed().map(|elapsed| elapsed < ttl).unwrap_or(true)
+        });
+    }
+}
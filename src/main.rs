@@ -3,7 +3,9 @@ extern crate biowiki;
 
 use std::env;
 use std::path::PathBuf;
+use std::time::Duration;
 use getopts::Options;
+use biowiki::{Config, FileOffload, StorageFormat, TlsConfig, MAX_BROTLI_QUALITY};
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
@@ -17,7 +19,32 @@ fn main() {
     let mut opts = Options::new();
     opts.optopt("h", "host", "listen on host (default: localhost)", "HOST");
     opts.optopt("p", "port", "listen on port (default: 3000)", "PORT");
+    opts.optopt("", "listen", "listen on these comma-separated addresses instead of --host and --port", "ADDRS");
     opts.reqopt("d", "dir", "directory for wiki files", "PATH");
+    opts.optopt("", "max-content-length", "maximum page content size in bytes (default: 1048576)", "BYTES");
+    opts.optopt("", "max-body-size", "maximum request body size in bytes (default: 1048576)", "BYTES");
+    opts.optopt("", "cache-capacity", "number of parsed pages to keep in memory, 0 to disable (default: 256)", "PAGES");
+    opts.optopt("", "max-pages-per-web", "refuse to create pages in a web that already has this many", "PAGES");
+    opts.optopt("", "brotli-quality", "brotli compression quality, 0-11 (default: 5)", "LEVEL");
+    opts.optopt("", "request-timeout", "seconds to wait for a request body (default: 30)", "SECS");
+    opts.optflag("", "case-insensitive-pages", "redirect page lookups that differ only in case");
+    opts.optopt("", "access-log", "append a JSON line per request to this file", "PATH");
+    opts.optopt("", "rate-limit-read", "allow each client this many GET and HEAD requests per minute", "N");
+    opts.optopt("", "rate-limit-write", "allow each client this many other requests per minute", "N");
+    opts.optflag("", "trust-forwarded-for", "identify clients by X-Forwarded-For, when behind a proxy");
+    opts.optopt("", "auth", "require HTTP Basic auth for users in this JSON file", "PATH");
+    opts.optopt("", "tokens", "accept bearer tokens listed in this JSON file", "PATH");
+    opts.optflag("", "init", "create a Home web with a WebHome page if the directory is empty");
+    opts.optopt("", "storage", "page file format: json or markdown (default: json)", "FORMAT");
+    opts.optflag("", "durable", "fsync files and directories after every write (slower)");
+    opts.optflag("", "retry-writes", "retry writes that fail with transient errors, as on NFS");
+    opts.optflag("", "allow-symlinks", "serve webs and pages whose directories are symbolic links");
+    opts.optopt("", "attachments-dir", "keep attachments under this directory instead of inside pages", "PATH");
+    opts.optopt("", "accel-redirect", "let nginx send attachments from this internal location", "PREFIX");
+    opts.optflag("", "sendfile", "let the front server send attachments named by X-Sendfile");
+    opts.optflag("", "http2", "also accept HTTP/2 connections from clients with prior knowledge");
+    opts.optopt("", "cert", "serve HTTPS using this PEM certificate (requires --key)", "PATH");
+    opts.optopt("", "key", "PKCS#8 PEM private key for --cert", "PATH");
     opts.optflag("", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -32,20 +59,6 @@ fn main() {
         return;
     }
 
-    let host =
-        if matches.opt_present("h") {
-            matches.opt_str("h").unwrap()
-        } else {
-            "127.0.0.1".to_string()
-        };
-
-    let port =
-        if matches.opt_present("p") {
-            matches.opt_str("p").unwrap()
-        } else {
-            "3000".to_string()
-        };
-
     let dir = matches.opt_str("d").unwrap();
     let path = PathBuf::from(dir);
     if !path.is_dir() {
@@ -53,5 +66,162 @@ fn main() {
         return;
     }
 
-    biowiki::run(host, port, path);
+    let mut config = Config::new(path);
+    if let Some(host) = matches.opt_str("h") {
+        config.host = host;
+    }
+    if let Some(port) = matches.opt_str("p") {
+        config.port = port;
+    }
+    if let Some(listen) = matches.opt_str("listen") {
+        for addr in listen.split(',') {
+            match addr.trim().parse() {
+                Ok(addr) => config.listen.push(addr),
+                Err(_) => {
+                    println!("invalid --listen address: {}", addr);
+                    return;
+                }
+            }
+        }
+    }
+    if let Some(max) = matches.opt_str("max-content-length") {
+        match max.parse() {
+            Ok(max) => config.max_content_length = max,
+            Err(_) => {
+                println!("invalid --max-content-length: {}", max);
+                return;
+            }
+        }
+    }
+    if let Some(max) = matches.opt_str("max-body-size") {
+        match max.parse() {
+            Ok(max) => config.max_body_size = max,
+            Err(_) => {
+                println!("invalid --max-body-size: {}", max);
+                return;
+            }
+        }
+    }
+    if let Some(capacity) = matches.opt_str("cache-capacity") {
+        match capacity.parse() {
+            Ok(capacity) => config.cache_capacity = capacity,
+            Err(_) => {
+                println!("invalid --cache-capacity: {}", capacity);
+                return;
+            }
+        }
+    }
+    if let Some(max) = matches.opt_str("max-pages-per-web") {
+        match max.parse() {
+            Ok(max) => config.max_pages_per_web = Some(max),
+            Err(_) => {
+                println!("invalid --max-pages-per-web: {}", max);
+                return;
+            }
+        }
+    }
+    if let Some(quality) = matches.opt_str("brotli-quality") {
+        match quality.parse() {
+            Ok(quality) if quality <= MAX_BROTLI_QUALITY => config.brotli_quality = quality,
+            _ => {
+                println!("invalid --brotli-quality: {}", quality);
+                return;
+            }
+        }
+    }
+    if let Some(timeout) = matches.opt_str("request-timeout") {
+        match timeout.parse() {
+            Ok(timeout) if timeout > 0 => config.request_timeout = Duration::from_secs(timeout),
+            _ => {
+                println!("invalid --request-timeout: {}", timeout);
+                return;
+            }
+        }
+    }
+    if matches.opt_present("case-insensitive-pages") {
+        config.case_insensitive_pages = true;
+    }
+    if let Some(access_log) = matches.opt_str("access-log") {
+        config.access_log = Some(PathBuf::from(access_log));
+    }
+    if let Some(limit) = matches.opt_str("rate-limit-read") {
+        match limit.parse() {
+            Ok(limit) if limit > 0 => config.rate_limit_read = Some(limit),
+            _ => {
+                println!("invalid --rate-limit-read: {}", limit);
+                return;
+            }
+        }
+    }
+    if let Some(limit) = matches.opt_str("rate-limit-write") {
+        match limit.parse() {
+            Ok(limit) if limit > 0 => config.rate_limit_write = Some(limit),
+            _ => {
+                println!("invalid --rate-limit-write: {}", limit);
+                return;
+            }
+        }
+    }
+    if matches.opt_present("trust-forwarded-for") {
+        config.trust_forwarded_for = true;
+    }
+    if let Some(format) = matches.opt_str("storage") {
+        match StorageFormat::parse(&format) {
+            Some(format) => config.storage.format = format,
+            None => {
+                println!("invalid --storage: {}", format);
+                return;
+            }
+        }
+    }
+    if let Some(auth) = matches.opt_str("auth") {
+        config.auth = Some(PathBuf::from(auth));
+    }
+    if let Some(dir) = matches.opt_str("attachments-dir") {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            println!("{} is not a directory", dir.display());
+            return;
+        }
+        config.storage.attachments_root = Some(dir);
+    }
+    match (matches.opt_str("accel-redirect"), matches.opt_present("sendfile")) {
+        (Some(_), true) => {
+            println!("--accel-redirect and --sendfile cannot be used together");
+            return;
+        },
+        (Some(prefix), false) => config.file_offload = Some(FileOffload::AccelRedirect(prefix)),
+        (None, true) => config.file_offload = Some(FileOffload::Sendfile),
+        (None, false) => ()
+    }
+    if matches.opt_present("http2") {
+        config.http2 = true;
+    }
+    if matches.opt_present("durable") {
+        config.storage.durable = true;
+    }
+    if matches.opt_present("allow-symlinks") {
+        config.storage.allow_symlinks = true;
+    }
+    if matches.opt_present("retry-writes") {
+        config.storage.retry_writes = true;
+    }
+    if let Some(tokens) = matches.opt_str("tokens") {
+        config.tokens = Some(PathBuf::from(tokens));
+    }
+    if matches.opt_present("init") {
+        config.init = true;
+    }
+    match (matches.opt_str("cert"), matches.opt_str("key")) {
+        (Some(cert), Some(key)) => {
+            config.tls = Some(TlsConfig { cert: PathBuf::from(cert), key: PathBuf::from(key) });
+        },
+        (None, None) => (),
+        _ => {
+            println!("--cert and --key must be given together");
+            return;
+        }
+    }
+
+    biowiki::run(config);
 }
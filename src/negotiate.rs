@@ -0,0 +1,57 @@
+use hyper::Request;
+use hyper::header::{q, Accept, ContentType};
+use mime;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    Html
+}
+
+// Pick a representation from the Accept header, honoring quality values.
+// A missing header means JSON; `None` means nothing acceptable is offered.
+pub fn negotiate(request: &Request) -> Option<Format> {
+    let accept = match request.headers().get::<Accept>() {
+        Some(accept) => accept,
+        None => return Some(Format::Json)
+    };
+
+    let mut items: Vec<_> = accept.iter().filter(|item| item.quality > q(0)).collect();
+    items.sort_by(|a, b| b.quality.cmp(&a.quality));
+    for item in items {
+        let format = match (item.item.type_(), item.item.subtype()) {
+            (mime::APPLICATION, mime::JSON) |
+                (mime::APPLICATION, mime::STAR) |
+                (mime::STAR, mime::STAR) => Some(Format::Json),
+            (mime::TEXT, mime::HTML) | (mime::TEXT, mime::STAR) => Some(Format::Html),
+            _ => None
+        };
+        if format.is_some() {
+            return format;
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PatchFormat {
+    // fields present in the body overwrite the page's
+    Merge,
+    // RFC 7386 JSON merge patch
+    MergePatch
+}
+
+// How a PATCH body should be applied, from its Content-Type. A missing
+// header keeps the plain field merge; `None` means an unsupported type.
+pub fn patch_format(request: &Request) -> Option<PatchFormat> {
+    let content_type = match request.headers().get::<ContentType>() {
+        Some(&ContentType(ref content_type)) => content_type,
+        None => return Some(PatchFormat::Merge)
+    };
+
+    match (content_type.type_(), content_type.subtype().as_str(), content_type.suffix()) {
+        (mime::APPLICATION, "json", None) => Some(PatchFormat::Merge),
+        (mime::APPLICATION, "merge-patch", Some(mime::JSON)) => Some(PatchFormat::MergePatch),
+        _ => None
+    }
+}
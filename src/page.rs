@@ -1,18 +1,118 @@
 use std::error;
 use std::fmt::{self, Write as FmtWrite};
-use std::io::{self, Write as IoWrite};
+use std::io::{self, Read, Write as IoWrite};
 use std::convert::From;
-use std::path::PathBuf;
-use std::fs::{self, File};
-use serde_json;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::fs::{self, File, OpenOptions};
+use serde_json::{self, Map, Value};
 use sha2::{Sha256};
 use digest::{Input, FixedOutput};
 
+use pulldown_cmark::{html, Parser};
+use ammonia;
+use base64;
+use mime;
+use regex::{Captures, Regex};
+use chrono::{SecondsFormat, Utc};
+use uuid::Uuid;
+
 use attachment::*;
+use links::{self, rewrite_wiki_links, wiki_links};
+use router::decode_param;
+use diff::{diff_lines, DiffLine};
+use web::WEB_FILENAME;
+use store::{Storage, JSON_FILENAME, MARKDOWN_FILENAME};
+
+pub const ATTACHMENTS_DIRECTORY: &'static str = "attachments";
+pub const VERSIONS_DIRECTORY: &'static str = "versions";
+pub const AUDIT_FILENAME: &'static str = "audit.log";
+const MAX_TITLE_LENGTH: usize = 200;
+// shorter version hash prefixes only match a whole hash
+const MIN_VERSION_PREFIX: usize = 4;
+pub const DEFAULT_CONTENT_TYPE: &'static str = "text/markdown";
+const CONTENT_TYPES: [&'static str; 3] = [DEFAULT_CONTENT_TYPE, "text/html", "text/plain"];
+// total size of the attachments inlined into an exported page
+pub const MAX_EXPORT_EMBED_BYTES: u64 = 10 * 1024 * 1024;
+const RESERVED_NAMES: [&'static str; 6] = [
+    JSON_FILENAME, MARKDOWN_FILENAME, ATTACHMENTS_DIRECTORY, VERSIONS_DIRECTORY, WEB_FILENAME, BLOBS_DIRECTORY
+];
+
+// Names become directory names on disk, so anything that could step
+// outside its parent or collide with the page layout is rejected.
+pub fn is_valid_name(name: &str) -> bool {
+    if name.is_empty() || name == "." || name == ".." {
+        return false;
+    }
+    if name.contains('/') || name.contains('\\') {
+        return false;
+    }
+    !RESERVED_NAMES.contains(&name)
+}
+
+// Listing order: case-insensitive, with an exact comparison to break
+// ties, so the same entries always come back in the same order.
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b))
+}
+
+// Write to a temporary file next to the target and rename it into place,
+// so a crash mid-write never leaves a truncated file behind. Each write
+// has a temporary file of its own, so concurrent writers of the same
+// file cannot disturb each other. With `sync`, the data and then the
+// rename are flushed to disk before returning.
+pub fn write_atomic(path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+    write_atomic_with(path, sync, |file| file.write_all(data))
+}
 
-const PAGE_FILENAME: &'static str = "page.json";
-const ATTACHMENTS_DIRECTORY: &'static str = "attachments";
-const VERSIONS_DIRECTORY: &'static str = "versions";
+// `write_atomic` with `fill` writing the temporary file's content.
+fn write_atomic_with<F>(path: &Path, sync: bool, fill: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()>
+{
+    let mut tmp_path = path.to_path_buf();
+    let tmp_name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!(".{}.{}.tmp", name, Uuid::new_v4()),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))
+    };
+    tmp_path.set_file_name(tmp_name);
+
+    let result = File::create(&tmp_path).
+        and_then(|mut file| {
+            fill(&mut file)?;
+            if sync {
+                file.sync_all()?;
+            }
+            Ok(())
+        }).
+        and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+    if sync {
+        sync_parent(path)?;
+    }
+    Ok(())
+}
+
+// Whether an existing blob can stand in for new content. Its mtime is
+// brought up to date first, so that garbage collection, which spares
+// recent blobs, does not remove it before the new pointer is written.
+fn reuse_blob(path: &Path) -> bool {
+    OpenOptions::new().write(true).open(path).
+        and_then(|file| file.set_modified(SystemTime::now())).
+        is_ok()
+}
+
+// Flush the directory entry for `path`, so a newly created or renamed
+// file or directory survives a crash.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub enum PageError {
@@ -21,9 +121,16 @@ pub enum PageError {
     InvalidPath,
     Utf8Error,
     NameMismatch,
+    InvalidName,
+    Ambiguous(Vec<String>),
+    Validation(String),
+    InvalidFormat(String),
     IoError(io::Error),
     JsonError(serde_json::error::Error),
-    OverwriteError
+    OverwriteError,
+    CurrentVersion,
+    QuotaExceeded(usize),
+    Symlink
 }
 
 impl error::Error for PageError {
@@ -34,9 +141,16 @@ impl error::Error for PageError {
             &PageError::InvalidPath => "page path is not valid",
             &PageError::Utf8Error => "page name is not a valid utf8 string",
             &PageError::NameMismatch => "page detail name does not match",
+            &PageError::InvalidName => "page name is not valid",
+            &PageError::Ambiguous(_) => "page name matches more than one page",
+            &PageError::Validation(ref message) => message,
+            &PageError::InvalidFormat(ref message) => message,
             &PageError::IoError(ref err) => err.description(),
             &PageError::JsonError(ref err) => err.description(),
             &PageError::OverwriteError => "page already exists",
+            &PageError::CurrentVersion => "version is the current page content",
+            &PageError::QuotaExceeded(_) => "web has reached its page limit",
+            &PageError::Symlink => "page path is a symbolic link",
         }
     }
 }
@@ -49,9 +163,16 @@ impl fmt::Display for PageError {
             &PageError::InvalidPath => write!(f, "PageError::InvalidPath"),
             &PageError::Utf8Error => write!(f, "PageError::Utf8Error"),
             &PageError::NameMismatch => write!(f, "PageError::NameMismatch"),
+            &PageError::InvalidName => write!(f, "PageError::InvalidName"),
+            &PageError::Ambiguous(ref names) => write!(f, "PageError::Ambiguous({})", names.join(", ")),
+            &PageError::Validation(ref message) => write!(f, "PageError::Validation({})", message),
+            &PageError::InvalidFormat(ref message) => write!(f, "PageError::InvalidFormat({})", message),
             &PageError::IoError(ref err) => write!(f, "PageError::IoError({})", err),
             &PageError::JsonError(ref err) => write!(f, "PageError::JsonError({})", err),
             &PageError::OverwriteError => write!(f, "PageError::OverwriteError"),
+            &PageError::CurrentVersion => write!(f, "PageError::CurrentVersion"),
+            &PageError::QuotaExceeded(limit) => write!(f, "PageError::QuotaExceeded({})", limit),
+            &PageError::Symlink => write!(f, "PageError::Symlink"),
         }
     }
 }
@@ -71,29 +192,271 @@ impl From<serde_json::error::Error> for PageError {
     }
 }
 
+pub fn default_content_type() -> String {
+    DEFAULT_CONTENT_TYPE.to_string()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn content_type_errors(content_type: &str) -> Option<String> {
+    if CONTENT_TYPES.contains(&content_type) {
+        None
+    } else {
+        Some(format!("content_type must be one of {}", CONTENT_TYPES.join(", ")))
+    }
+}
+
+// Markdown passes raw HTML through, so it is sanitized along with HTML
+// content: scripts, iframes, event handlers and `javascript:` URLs are
+// dropped, while formatting and relative links are kept. Wiki links
+// resolve against `web_name`.
+pub fn render_content(content: &str, content_type: &str, web_name: &str) -> String {
+    match content_type {
+        "text/html" => ammonia::clean(content),
+        "text/plain" => format!("<pre>{}</pre>\n", escape_html(content)),
+        _ => {
+            let markdown = rewrite_wiki_links(content, web_name);
+            let mut output = String::new();
+            html::push_html(&mut output, Parser::new(&markdown));
+            ammonia::clean(&output)
+        }
+    }
+}
+
+// Content to render without saving it anywhere.
+#[derive(Deserialize)]
+pub struct PreviewData {
+    pub content: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String
+}
+
+impl PreviewData {
+    pub fn parse(data: &[u8]) -> Result<PreviewData, PageError> {
+        let preview_data = serde_json::from_slice::<PreviewData>(data)?;
+        Ok(preview_data)
+    }
+
+    pub fn validate(&self, max_content_length: usize) -> Result<(), PageError> {
+        if self.content.len() > max_content_length {
+            return Err(PageError::Validation(format!("content must not exceed {} bytes", max_content_length)));
+        }
+        match content_type_errors(&self.content_type) {
+            Some(message) => Err(PageError::Validation(message)),
+            None => Ok(())
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct PageDetail {
     pub name: String,
     pub title: String,
     content: String,
-    parent: String
+    // pages written before this field existed are Markdown
+    #[serde(default = "default_content_type")]
+    content_type: String,
+    parent: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>
 }
 
 impl PageDetail {
+    pub fn new(name: String, title: String, content: String, parent: String, tags: Vec<String>) -> PageDetail {
+        PageDetail { name, title, content, content_type: default_content_type(), parent, tags }
+    }
+
     pub fn parse(data: &[u8]) -> Result<PageDetail, PageError> {
         let detail = serde_json::from_slice::<PageDetail>(data)?;
         Ok(detail)
     }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn set_content(&mut self, content: &str) {
+        self.content = content.to_string();
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.content_type = content_type.to_string();
+    }
+
+    pub fn parent(&self) -> &str {
+        &self.parent
+    }
+
+    pub fn set_parent(&mut self, parent: &str) {
+        self.parent = parent.to_string();
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn render_html(&self, web_name: &str) -> String {
+        render_content(&self.content, &self.content_type, web_name)
+    }
+
+    // Case-insensitive match against the name, title and content;
+    // `query` must already be lowercased.
+    pub fn matches(&self, query: &str) -> bool {
+        self.name.to_lowercase().contains(query) ||
+            self.title.to_lowercase().contains(query) ||
+            self.content.to_lowercase().contains(query)
+    }
+
+    pub fn validate(&self, max_content_length: usize) -> Result<(), PageError> {
+        match self.validation_errors(max_content_length).into_iter().next() {
+            Some(message) => Err(PageError::Validation(message)),
+            None => Ok(())
+        }
+    }
+
+    // Every rule the detail breaks, in a stable order.
+    pub fn validation_errors(&self, max_content_length: usize) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.name.is_empty() {
+            errors.push("name must not be empty".to_string());
+        } else if !is_valid_name(&self.name) {
+            errors.push(format!("name {:?} is not allowed", self.name));
+        }
+        let title_length = self.title.chars().count();
+        if title_length == 0 || title_length > MAX_TITLE_LENGTH {
+            errors.push(format!("title must be between 1 and {} characters", MAX_TITLE_LENGTH));
+        }
+        if self.content.len() > max_content_length {
+            errors.push(format!("content must not exceed {} bytes", max_content_length));
+        }
+        if let Some(message) = content_type_errors(&self.content_type) {
+            errors.push(message);
+        }
+        errors
+    }
+
+    // Overlay the fields present in a JSON object onto a copy of this
+    // detail; fields missing from the object keep their current values.
+    pub fn merge(&self, data: &[u8]) -> Result<PageDetail, PageError> {
+        let mut changes = serde_json::from_slice::<Map<String, Value>>(data)?;
+        let text = TextChanges::take(&mut changes);
+        let mut merged = serde_json::to_value(self)?;
+        if let Value::Object(ref mut fields) = merged {
+            for (key, value) in changes {
+                fields.insert(key, value);
+            }
+        }
+        let mut detail = serde_json::from_value(merged)?;
+        text.apply(&mut detail);
+        Ok(detail)
+    }
+
+    // Apply an RFC 7386 merge patch to a copy of this detail: `null`
+    // removes a field, objects merge recursively and anything else,
+    // arrays included, replaces the current value.
+    pub fn merge_patch(&self, data: &[u8]) -> Result<PageDetail, PageError> {
+        let mut patch = serde_json::from_slice::<Value>(data)?;
+        let text = match patch {
+            Value::Object(ref mut changes) => TextChanges::take(changes),
+            _ => TextChanges::default()
+        };
+        let mut patched = serde_json::to_value(self)?;
+        apply_merge_patch(&mut patched, patch);
+        let mut detail = serde_json::from_value(patched)?;
+        text.apply(&mut detail);
+        Ok(detail)
+    }
+}
+
+// New `content` and `parent` strings taken out of a patch so they can be
+// set directly rather than round-tripped through JSON. Anything else
+// under those keys, such as a `null`, is left in the patch to be
+// handled, or rejected, with the other fields.
+#[derive(Default)]
+struct TextChanges {
+    content: Option<String>,
+    parent: Option<String>
+}
+
+impl TextChanges {
+    fn take(changes: &mut Map<String, Value>) -> TextChanges {
+        TextChanges {
+            content: take_string(changes, "content"),
+            parent: take_string(changes, "parent")
+        }
+    }
+
+    fn apply(self, detail: &mut PageDetail) {
+        if let Some(content) = self.content {
+            detail.set_content(&content);
+        }
+        if let Some(parent) = self.parent {
+            detail.set_parent(&parent);
+        }
+    }
+}
+
+fn take_string(changes: &mut Map<String, Value>, key: &str) -> Option<String> {
+    match changes.remove(key) {
+        Some(Value::String(value)) => Some(value),
+        Some(value) => {
+            changes.insert(key.to_string(), value);
+            None
+        },
+        None => None
+    }
+}
+
+fn apply_merge_patch(target: &mut Value, patch: Value) {
+    let changes = match patch {
+        Value::Object(changes) => changes,
+        patch => {
+            *target = patch;
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(ref mut fields) = *target {
+        for (key, value) in changes {
+            if value.is_null() {
+                fields.remove(&key);
+            } else {
+                apply_merge_patch(fields.entry(key).or_insert(Value::Null), value);
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Page {
     pub path: PathBuf,
-    pub detail: PageDetail
+    pub detail: PageDetail,
+    pub storage: Storage
 }
 
 impl Page {
-    pub fn open(path: PathBuf) -> Result<Page, PageError> {
+    pub fn open(path: PathBuf, storage: Storage) -> Result<Page, PageError> {
+        if storage.refuses_link(&path) {
+            return Err(PageError::Symlink);
+        }
         if !path.exists() {
             return Err(PageError::NotFound);
         }
@@ -113,96 +476,117 @@ impl Page {
                 Some(s) => s.to_string()
             }
         };
-        let detail: PageDetail = {
+        let detail = {
             let mut detail_path = path.clone();
-            detail_path.push(PAGE_FILENAME);
-            let detail_file = File::open(&detail_path)?;
-            serde_json::from_reader(detail_file)?
+            detail_path.push(storage.store().file_name());
+            if storage.refuses_link(&detail_path) {
+                return Err(PageError::Symlink);
+            }
+            let mut data = Vec::new();
+            File::open(&detail_path)?.read_to_end(&mut data)?;
+            storage.store().decode(&data)?
         };
         if &detail.name != &expected_name {
             return Err(PageError::NameMismatch);
         }
 
-        Ok(Page { path, detail })
+        Ok(Page { path, detail, storage })
     }
 
-    pub fn create(&self) -> Result<(), PageError> {
-        if self.path.exists() {
-            return Err(PageError::OverwriteError);
+    // Both return the hash of the version that was written.
+    pub fn create(&self) -> Result<String, PageError> {
+        if !is_valid_name(&self.detail.name) {
+            return Err(PageError::InvalidName);
+        }
+        // let create_dir decide, so two racing requests cannot both succeed
+        match self.storage.create_dir(&self.path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(PageError::OverwriteError);
+            },
+            Err(err) => return Err(err.into())
         }
-        fs::create_dir(&self.path)?;
         self.write()
     }
 
-    pub fn update(&self) -> Result<(), PageError> {
+    pub fn update(&self) -> Result<String, PageError> {
         if !self.path.exists() {
             return Err(PageError::NotFound);
         }
         self.write()
     }
 
+    // Bump the detail file's modification time, which is what clients see
+    // as the page's last update, without writing a new version.
+    pub fn touch(&self) -> Result<(), PageError> {
+        let file = OpenOptions::new().write(true).open(self.page_path())?;
+        file.set_modified(SystemTime::now())?;
+        if self.storage.durable {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
     fn page_path(&self) -> PathBuf {
         let mut page_path = self.path.clone();
-        page_path.push(PAGE_FILENAME);
+        page_path.push(self.storage.store().file_name());
         page_path
     }
 
+    // The versions directory, unless it is a link that is refused.
+    fn versions_path(&self) -> Result<PathBuf, PageError> {
+        let path = self.path.join(VERSIONS_DIRECTORY);
+        if self.storage.refuses_link(&path) {
+            return Err(PageError::Symlink);
+        }
+        Ok(path)
+    }
+
     fn version_path(&self, hash: &str) -> PathBuf {
         let mut version_path = self.path.clone();
         version_path.push(VERSIONS_DIRECTORY);
-        let file_name = format!("{}.json", &hash);
+        let file_name = format!("{}.{}", &hash, self.storage.store().version_extension());
         version_path.push(&file_name);
         version_path
     }
 
-    fn write(&self) -> Result<(), PageError> {
-        let data = serde_json::to_string_pretty(&self.detail)?;
+    fn write(&self) -> Result<String, PageError> {
+        let data = self.storage.store().encode(&self.detail)?;
         let data = data.as_ref();
+        let versions_path = self.versions_path()?;
 
         // write main file
-        {
-            let page_path = self.page_path();
-            let mut page_file = File::create(page_path)?;
-            page_file.write_all(data)?;
-        }
+        self.storage.write_file(&self.page_path(), data)?;
 
-        // write version file
-        {
-            let mut hasher = Sha256::default();
-            hasher.process(data);
-            let result = hasher.fixed_result();
-            let mut hash = String::new();
-            for byte in result {
-                write!(&mut hash, "{:x}", byte).expect("Unable to write");
-            }
-            let version_path = self.version_path(&hash);
-            {
-                let versions_path = version_path.parent().unwrap();
-                if !versions_path.exists() {
-                    fs::create_dir(versions_path)?;
-                }
-            }
-            if !version_path.exists() {
-                let mut version_file = File::create(version_path)?;
-                version_file.write_all(data)?;
+        // write version file; a concurrent write of the same content may
+        // create the directory or the file first, which is as good
+        let hash = version_hash(data);
+        let version_path = self.version_path(&hash);
+        if !versions_path.exists() {
+            match self.storage.create_dir(&versions_path) {
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+                result => result?
             }
         }
-        Ok(())
+        if !version_path.exists() {
+            self.storage.create_file(&version_path, data)?;
+        }
+        Ok(hash)
     }
 
     pub fn list_attachments(&self) -> Result<Vec<AttachmentStub>, AttachmentError> {
-        let mut path = self.path.clone();
-        path.push(ATTACHMENTS_DIRECTORY);
+        let path = self.attachments_path()?;
         if !path.exists() {
             return Ok(Vec::new());
         }
 
-        let stubs = fs::read_dir(&path)?.filter(|entry| {
+        let mut stubs: Vec<_> = fs::read_dir(&path)?.filter(|entry| {
             match entry {
                 &Err(_) => false,
                 &Ok(ref entry) => {
                     let path = entry.path();
-                    if !path.is_file() {
+                    // hidden files are uploads still in progress
+                    if !path.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
                         return false;
                     }
                     let s = path.to_str();
@@ -213,71 +597,635 @@ impl Page {
             let file_name = entry.unwrap().path().file_name().unwrap().to_str().unwrap().to_string();
             AttachmentStub { file_name }
         }).collect();
+        stubs.sort_by(|a, b| compare_names(&a.file_name, &b.file_name));
         Ok(stubs)
     }
 
+    // Distinct `[[...]]` targets in the content, in the order they first
+    // appear.
+    pub fn outbound_links(&self) -> Vec<String> {
+        let mut targets = Vec::new();
+        for (_, target) in wiki_links(self.detail.content()) {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        targets
+    }
+
+    // The page as a standalone HTML document. Images showing its own
+    // attachments are inlined as data URIs until `embed_limit` bytes have
+    // been embedded; the rest become `attachments/NAME` links, which work
+    // when the export is saved next to the attachment files.
+    pub fn export_html(&self, web_name: &str, embed_limit: u64) -> String {
+        lazy_static! {
+            static ref IMG_SRC_RE: Regex = Regex::new(r#"(<img\s[^>]*?src=")([^"]*)(")"#).unwrap();
+        }
+        let prefix = format!("{}/{}/", links::page_url(web_name, &self.detail.name), ATTACHMENTS_DIRECTORY);
+        let relative_prefix = format!("{}/", ATTACHMENTS_DIRECTORY);
+        let mut embedded = 0;
+        let html = self.detail.render_html(web_name);
+        let body = IMG_SRC_RE.replace_all(&html, |caps: &Captures| {
+            let src = caps[2].replace("&amp;", "&");
+            let encoded_name =
+                if src.starts_with(&prefix) {
+                    &src[prefix.len()..]
+                } else if src.starts_with(&relative_prefix) {
+                    &src[relative_prefix.len()..]
+                } else {
+                    return caps[0].to_string();
+                };
+            let attachment = decode_param(encoded_name).
+                and_then(|name| self.get_attachment(&name).ok().map(|attachment| (name, attachment)));
+            let (name, attachment) = match attachment {
+                Some((name, ref attachment)) if attachment.mime_type().type_() == mime::IMAGE => (name, attachment),
+                _ => return caps[0].to_string()
+            };
+            let size = attachment.size().unwrap_or(u64::max_value());
+            let data = if embedded + size <= embed_limit { fs::read(attachment.data_path()).ok() } else { None };
+            // this runs after sanitizing, so what is added must be escaped
+            let src = match data {
+                Some(data) => {
+                    embedded += data.len() as u64;
+                    let mime_type = attachment.mime_type();
+                    let suffix = mime_type.suffix().map_or(String::new(), |suffix| format!("+{}", suffix));
+                    format!("data:{}/{}{};base64,{}", mime_type.type_(), mime_type.subtype(), suffix, base64::encode(&data))
+                },
+                None => format!("{}/{}", ATTACHMENTS_DIRECTORY, links::encode_path_segment(&name))
+            };
+            format!("{}{}{}", &caps[1], escape_html(&src), &caps[3])
+        });
+        let title = escape_html(&self.detail.title);
+        format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n{}</body>\n</html>\n",
+                title, title, body)
+    }
+
+    fn blobs_path(&self) -> PathBuf {
+        self.storage.blobs_path(self.path.parent().unwrap())
+    }
+
+    // The attachments directory, unless it or the blobs directory is a
+    // link that is refused.
+    fn attachments_path(&self) -> Result<PathBuf, AttachmentError> {
+        let path = self.storage.attachments_path(&self.path);
+        if self.storage.refuses_link(&path) || self.storage.refuses_link(&self.blobs_path()) {
+            return Err(AttachmentError::Symlink);
+        }
+        Ok(path)
+    }
+
     pub fn get_attachment(&self, file_name: &str) -> Result<Attachment, AttachmentError> {
-        let mut path = self.path.clone();
-        path.push(ATTACHMENTS_DIRECTORY);
+        let mut path = self.attachments_path()?;
         path.push(file_name);
-        Attachment::open(path)
+        Attachment::open(path, &self.blobs_path(), &self.storage)
     }
 
-    pub fn save_attachment(&self, att_data: AttachmentData) -> Result<(), AttachmentError> {
+    // Identical content is stored once; blobs no longer referenced by any
+    // pointer are left in place for a later clean-up pass. An existing
+    // attachment of the same name is only replaced with `overwrite`.
+    pub fn save_attachment(&self, att_data: AttachmentData, overwrite: bool) -> Result<(), AttachmentError> {
+        let mut att_path = self.attachments_path()?;
+        att_path.push(&att_data.file_name);
+        if !overwrite && att_path.exists() {
+            return Err(AttachmentError::OverwriteError);
+        }
+
         let data = att_data.data()?;
+        let hash = content_hash(&data);
 
-        let mut att_path = self.path.clone();
-        att_path.push(ATTACHMENTS_DIRECTORY);
-        if !att_path.exists() {
-            fs::create_dir(&att_path)?;
+        let blobs_path = self.blobs_path();
+        if !blobs_path.exists() {
+            self.storage.create_dir_all(&blobs_path)?;
+        }
+        let blob_path = blobs_path.join(&hash);
+        if !reuse_blob(&blob_path) {
+            self.storage.write_file(&blob_path, &data)?;
         }
-        att_path.push(att_data.file_name);
 
-        let mut att_file = File::create(att_path)?;
-        att_file.write_all(&data)?;
+        self.storage.create_dir_all(att_path.parent().unwrap())?;
+        let pointer = pointer_data(&hash, att_data.given_mime_type().as_ref());
+        self.storage.write_file(&att_path, pointer.as_bytes())?;
         Ok(())
     }
 
-    pub fn list_versions(&self) -> Result<Vec<VersionStub>, PageError> {
-        let mut path = self.path.clone();
-        path.push(VERSIONS_DIRECTORY);
-        if !path.exists() {
-            return Ok(Vec::new());
+    // Bytes received so far by a resumable upload of `file_name`.
+    pub fn upload_offset(&self, file_name: &str) -> Option<u64> {
+        let path = self.attachments_path().ok()?.join(part_file_name(file_name));
+        if self.storage.refuses_link(&path) {
+            return None;
         }
+        fs::metadata(&path).ok().map(|metadata| metadata.len())
+    }
 
-        let stubs = fs::read_dir(&path)?.filter(|entry| {
-            match entry {
-                &Err(_) => false,
-                &Ok(ref entry) => {
-                    let path = entry.path();
-                    if !path.is_file() {
-                        return false;
-                    }
-                    let s = path.to_str();
-                    s.is_some()
+    // Add a chunk to a resumable upload. It must start where the bytes
+    // received so far end, or at zero to start over, and give the same
+    // `total` as the chunk that started it. An existing
+    // attachment of that name can only be replaced with `overwrite`,
+    // which is checked when the upload starts. Once all `total` bytes are
+    // in, the upload becomes the attachment; the result says whether it
+    // has.
+    pub fn append_upload(&self, file_name: &str, start: u64, total: u64, data: &[u8], overwrite: bool) -> Result<bool, AttachmentError> {
+        let dir = self.attachments_path()?;
+        let part_name = part_file_name(file_name);
+        let part_path = dir.join(&part_name);
+        let total_path = dir.join(total_file_name(&part_name));
+        if start == 0 && !overwrite && dir.join(file_name).exists() {
+            return Err(AttachmentError::OverwriteError);
+        }
+        if start != 0 {
+            let received = self.upload_offset(file_name).unwrap_or(0);
+            if start != received {
+                return Err(AttachmentError::UnexpectedOffset(received));
+            }
+            let recorded = fs::read_to_string(&total_path).ok().and_then(|data| data.trim().parse().ok());
+            match recorded {
+                Some(recorded) if recorded != total => return Err(AttachmentError::TotalMismatch(recorded)),
+                _ => ()
+            }
+        }
+
+        self.storage.create_dir_all(&dir)?;
+        if start == 0 {
+            self.storage.write_file(&total_path, total.to_string().as_bytes())?;
+        }
+        {
+            let mut file =
+                if start == 0 {
+                    File::create(&part_path)?
+                } else {
+                    OpenOptions::new().append(true).open(&part_path)?
+                };
+            file.write_all(data)?;
+            if self.storage.durable {
+                file.sync_all()?;
+            }
+        }
+        if start + (data.len() as u64) < total {
+            return Ok(false);
+        }
+
+        // the finished upload becomes the blob, unless that content is
+        // already stored
+        let hash = file_hash(&part_path)?;
+        let blobs_path = self.blobs_path();
+        if !blobs_path.exists() {
+            self.storage.create_dir_all(&blobs_path)?;
+        }
+        let blob_path = blobs_path.join(&hash);
+        if reuse_blob(&blob_path) {
+            fs::remove_file(&part_path)?;
+        } else {
+            fs::rename(&part_path, &blob_path)?;
+            if self.storage.durable {
+                sync_parent(&blob_path)?;
+            }
+        }
+        let _ = fs::remove_file(&total_path);
+        self.storage.write_file(&dir.join(file_name), pointer_data(&hash, None).as_bytes())?;
+        Ok(true)
+    }
+
+    pub fn rename_attachment(&self, file_name: &str, new_name: &str) -> Result<(), AttachmentError> {
+        if !is_valid_file_name(new_name) {
+            return Err(AttachmentError::InvalidName);
+        }
+
+        let mut path = self.attachments_path()?;
+        let mut new_path = path.clone();
+        path.push(file_name);
+        new_path.push(new_name);
+        if !path.is_file() {
+            return Err(AttachmentError::NotFound);
+        }
+        if new_path.exists() {
+            return Err(AttachmentError::OverwriteError);
+        }
+        fs::rename(path, &new_path)?;
+        if self.storage.durable {
+            sync_parent(&new_path)?;
+        }
+        Ok(())
+    }
+
+    // Newest first by the modification time of each version file, with
+    // the hash breaking ties. `before` is the last hash of the previous
+    // page of results; a hash that is not a version is `NotFound`.
+    pub fn list_versions_page(&self, before: Option<&str>, limit: usize) -> Result<VersionList, PageError> {
+        let path = self.versions_path()?;
+        let mut dated = Vec::new();
+        if path.exists() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
                 }
+                let hash = match entry.path().file_stem().and_then(|stem| stem.to_str()) {
+                    Some(hash) => hash.to_string(),
+                    None => continue
+                };
+                dated.push((metadata.modified()?, VersionStub { hash }));
             }
-        }).map(|entry| {
-            let hash = entry.unwrap().path().file_stem().unwrap().to_str().unwrap().to_string();
-            VersionStub { hash }
-        }).collect();
-        Ok(stubs)
+        }
+        dated.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| compare_names(&a.1.hash, &b.1.hash)));
+
+        let start = match before {
+            Some(before) => match dated.iter().position(|&(_, ref stub)| stub.hash == before) {
+                Some(index) => index + 1,
+                None => return Err(PageError::NotFound)
+            },
+            None => 0
+        };
+        let mut versions: Vec<VersionStub> = dated.into_iter().skip(start).map(|(_, stub)| stub).collect();
+        let mut next_cursor = None;
+        if versions.len() > limit {
+            versions.truncate(limit);
+            next_cursor = versions.last().map(|stub| stub.hash.clone());
+        }
+        Ok(VersionList { versions, next_cursor })
+    }
+
+    // Counts the entries in the versions directory without reading them.
+    pub fn version_count(&self) -> Result<usize, PageError> {
+        let path = self.versions_path()?;
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // The full hash of the one version starting with `prefix`, as with
+    // abbreviated git ids. Several matches are `Ambiguous`.
+    pub fn resolve_version(&self, prefix: &str) -> Result<String, PageError> {
+        if self.version_path(prefix).is_file() {
+            return Ok(prefix.to_string());
+        }
+        if prefix.len() < MIN_VERSION_PREFIX || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(PageError::NotFound);
+        }
+        let mut hashes: Vec<String> = self.list_versions_page(None, usize::max_value())?.versions.
+            into_iter().
+            map(|stub| stub.hash).
+            filter(|hash| hash.starts_with(prefix)).
+            collect();
+        match hashes.len() {
+            0 => Err(PageError::NotFound),
+            1 => Ok(hashes.remove(0)),
+            _ => {
+                hashes.sort();
+                Err(PageError::Ambiguous(hashes))
+            }
+        }
     }
 
     pub fn get_version(&self, hash: &str) -> Result<PageDetail, PageError> {
+        let version_path = self.version_path(&self.resolve_version(hash)?);
+        if self.storage.refuses_link(&self.versions_path()?) || self.storage.refuses_link(&version_path) {
+            return Err(PageError::Symlink);
+        }
+        let mut data = Vec::new();
+        File::open(&version_path)?.read_to_end(&mut data)?;
+        self.storage.store().decode(&data)
+    }
+
+    // Appends one JSON line to the page's audit log. The change itself has
+    // already happened, so a failure here is only reported.
+    pub fn record_audit(&self, op: AuditOp, hash: &str, user: Option<&str>, request_id: &str) {
+        let entry = AuditEntry {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            request_id: Some(request_id.to_string()),
+            op,
+            hash: hash.to_string(),
+            user: user.map(|user| user.to_string())
+        };
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+        let path = self.path.join(AUDIT_FILENAME);
+        if self.storage.refuses_link(&path) {
+            println!("[{}] not writing audit log for {}: it is a symbolic link", request_id, self.path.display());
+            return;
+        }
+        let result = OpenOptions::new().create(true).append(true).
+            open(&path).
+            and_then(|mut file| file.write_all(&line));
+        if let Err(err) = result {
+            println!("[{}] unable to write audit log for {}: {}", request_id, self.path.display(), err);
+        }
+    }
+
+    // Oldest first; lines that do not parse are skipped.
+    pub fn audit_log(&self) -> Result<Vec<AuditEntry>, PageError> {
+        let path = self.path.join(AUDIT_FILENAME);
+        if self.storage.refuses_link(&path) {
+            return Err(PageError::Symlink);
+        }
+        let mut data = String::new();
+        match File::open(&path) {
+            Ok(mut file) => file.read_to_string(&mut data)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into())
+        };
+        Ok(data.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+
+    // The live page's own version is kept, so history always reaches it.
+    pub fn delete_version(&self, hash: &str) -> Result<(), PageError> {
+        self.versions_path()?;
+        let hash = &self.resolve_version(hash)?;
+        if self.content_hash()? == *hash {
+            return Err(PageError::CurrentVersion);
+        }
         let version_path = self.version_path(hash);
-        let version_file = File::open(&version_path)?;
-        let detail = serde_json::from_reader(version_file)?;
-        Ok(detail)
+        if !version_path.is_file() {
+            return Err(PageError::NotFound);
+        }
+        fs::remove_file(&version_path)?;
+        Ok(())
+    }
+
+    fn read_page_file(&self) -> Result<Vec<u8>, PageError> {
+        let mut data = Vec::new();
+        File::open(&self.page_path())?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // The hash of the live page file, which is also its version id.
+    pub fn content_hash(&self) -> Result<String, PageError> {
+        Ok(version_hash(&self.read_page_file()?))
+    }
+
+    // What changed between a stored version and the live page.
+    pub fn diff_versions(&self, hash: &str) -> Result<PageDiff, PageError> {
+        let hash = &self.resolve_version(hash)?;
+        let old = self.get_version(hash)?;
+        let data = self.read_page_file()?;
+        let new = self.storage.store().decode(&data)?;
+        Ok(PageDiff {
+            from: hash.to_string(),
+            to: version_hash(&data),
+            title: FieldChange::between(&old.title, &new.title),
+            parent: FieldChange::between(&old.parent, &new.parent),
+            content: diff_lines(&old.content, &new.content)
+        })
+    }
+}
+
+// Version ids are the SHA-256 of the stored page file. The digits are not
+// zero-padded, which existing version file names depend on.
+fn version_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.process(data);
+    let result = hasher.fixed_result();
+    let mut hash = String::new();
+    for byte in result {
+        write!(&mut hash, "{:x}", byte).expect("Unable to write");
+    }
+    hash
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOp {
+    Create,
+    Update,
+    Touch
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    timestamp: String,
+    // absent from entries written before request ids were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    op: AuditOp,
+    hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct FieldChange {
+    from: String,
+    to: String
+}
+
+impl FieldChange {
+    fn between(from: &str, to: &str) -> Option<FieldChange> {
+        if from == to {
+            None
+        } else {
+            Some(FieldChange { from: from.to_string(), to: to.to_string() })
+        }
     }
 }
 
+#[derive(Serialize)]
+pub struct PageDiff {
+    from: String,
+    to: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<FieldChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<FieldChange>,
+    content: Vec<DiffLine>
+}
+
 #[derive(Serialize)]
 pub struct PageStub {
-    pub name: String
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_count: Option<usize>,
+    // The outer Option says whether the field was asked for; the inner one
+    // is null when the page could not be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<Option<PageDetail>>
 }
 
 #[derive(Serialize)]
 pub struct VersionStub {
     hash: String
 }
+
+// `next_cursor` is passed back as `before` to fetch the following page,
+// and is null on the last one.
+#[derive(Serialize)]
+pub struct VersionList {
+    pub versions: Vec<VersionStub>,
+    pub next_cursor: Option<String>
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+    use store::Storage;
+    use testutil::ScratchDir;
+    use web::{ListSpec, Web, WebData, Webs};
+
+    fn detail(name: &str, content: &str) -> PageDetail {
+        PageDetail::new(name.to_string(), "Title".to_string(), content.to_string(), String::new(), Vec::new())
+    }
+
+    fn scratch_web(dir: &ScratchDir) -> Web {
+        let webs = Webs { path: dir.path().to_path_buf(), storage: Storage::new() };
+        webs.create_web(&WebData { name: "Main".to_string(), title: None, description: None }).unwrap()
+    }
+
+    #[test]
+    fn concurrent_identical_writes_all_succeed() {
+        let dir = ScratchDir::new();
+        let web = scratch_web(&dir);
+        web.new_page(detail("Home", "first")).create().unwrap();
+
+        let page = Arc::new(web.new_page(detail("Home", "same for everyone")));
+        let writers: Vec<_> = (0..16).map(|_| {
+            let page = page.clone();
+            thread::spawn(move || (0..20).map(|_| page.update()).collect::<Vec<_>>())
+        }).collect();
+        for writer in writers {
+            for result in writer.join().unwrap() {
+                assert!(result.is_ok(), "{:?}", result);
+            }
+        }
+        assert_eq!(web.get_page("Home").unwrap().detail.content(), "same for everyone");
+        assert_eq!(web.get_page("Home").unwrap().version_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn failed_update_keeps_the_old_page_file() {
+        let dir = ScratchDir::new();
+        let web = scratch_web(&dir);
+        let page = web.new_page(detail("Home", "first"));
+        page.create().unwrap();
+        let before = fs::read(page.page_path()).unwrap();
+
+        // Fail halfway through writing the new content, as a full disk
+        // would.
+        let result = write_atomic_with(&page.page_path(), false, |file| {
+            file.write_all(b"{\"name\": \"Ho")?;
+            Err(io::Error::new(io::ErrorKind::Other, "no space left on device"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(page.page_path()).unwrap(), before);
+
+        let leftovers: Vec<_> = fs::read_dir(&page.path).unwrap().
+            map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).
+            filter(|name| name.ends_with(".tmp")).
+            collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn listings_are_sorted_case_insensitively() {
+        let names = ["b", "A", "c"];
+        let dir = ScratchDir::new();
+        let webs = Webs { path: dir.path().to_path_buf(), storage: Storage::new() };
+        for name in names.iter() {
+            webs.create_web(&WebData { name: name.to_string(), title: None, description: None }).unwrap();
+        }
+        let listed: Vec<String> = webs.list_webs(&ListSpec::default()).unwrap().webs.into_iter().
+            map(|stub| stub.name).collect();
+        assert_eq!(listed, ["A", "b", "c"]);
+
+        let web = webs.get_web("A").unwrap();
+        for name in names.iter() {
+            web.new_page(detail(name, "content")).create().unwrap();
+        }
+        let listed: Vec<String> = web.list_pages().unwrap().into_iter().map(|stub| stub.name).collect();
+        assert_eq!(listed, ["A", "b", "c"]);
+
+        let page = web.get_page("A").unwrap();
+        for name in names.iter() {
+            let data = AttachmentData {
+                file_name: name.to_string(),
+                encoded_data: base64::encode(name.as_bytes()),
+                mime_type: None
+            };
+            page.save_attachment(data, false).unwrap();
+        }
+        let listed: Vec<String> = page.list_attachments().unwrap().into_iter().
+            map(|stub| stub.file_name).collect();
+        assert_eq!(listed, ["A", "b", "c"]);
+
+        // Versions are newest first, so give these the same old mtime and
+        // let the hash break the tie.
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        for name in names.iter() {
+            let path = page.version_path(name);
+            fs::write(&path, b"{}").unwrap();
+            fs::OpenOptions::new().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        }
+        let listed: Vec<String> = page.list_versions_page(None, 100).unwrap().versions.into_iter().
+            map(|stub| stub.hash).filter(|hash| names.contains(&hash.as_str())).collect();
+        assert_eq!(listed, ["A", "b", "c"]);
+    }
+
+    #[test]
+    fn merging_content_and_parent_replaces_them() {
+        let current = detail("Home", "the body");
+        let merged = current.merge(br#"{"content": "new body", "parent": "Index"}"#).unwrap();
+        assert_eq!(merged.content(), "new body");
+        assert_eq!(merged.parent(), "Index");
+        let patched = current.merge_patch(br#"{"content": "patched body"}"#).unwrap();
+        assert_eq!(patched.content(), "patched body");
+        assert_eq!(patched.title, "Title");
+        assert!(current.merge_patch(br#"{"content": null}"#).is_err());
+    }
+
+    #[test]
+    fn merging_only_the_title_keeps_the_content() {
+        let current = detail("Home", "the body");
+        let merged = current.merge(br#"{"title": "New title"}"#).unwrap();
+        assert_eq!(merged.title, "New title");
+        assert_eq!(merged.content(), "the body");
+        assert_eq!(merged.name, "Home");
+    }
+
+    const XSS_PAYLOADS: [&'static str; 4] = [
+        "<script>alert(1)</script>",
+        "<img src=\"x.png\" onerror=\"alert(1)\">",
+        "<a href=\"javascript:alert(1)\">click</a>",
+        "<iframe src=\"https://example.com/\"></iframe>"
+    ];
+
+    fn assert_harmless(html: &str) {
+        let lowercase = html.to_lowercase();
+        for needle in ["<script", "onerror", "javascript:", "<iframe"].iter() {
+            assert!(!lowercase.contains(needle), "{} survived in {}", needle, html);
+        }
+    }
+
+    #[test]
+    fn markdown_is_sanitized() {
+        for payload in XSS_PAYLOADS.iter() {
+            assert_harmless(&render_content(&format!("Some *text* {}", payload), DEFAULT_CONTENT_TYPE, "Main"));
+        }
+    }
+
+    #[test]
+    fn html_is_sanitized() {
+        for payload in XSS_PAYLOADS.iter() {
+            assert_harmless(&render_content(&format!("<p>Some <em>text</em> {}</p>", payload), "text/html", "Main"));
+        }
+    }
+
+    #[test]
+    fn wiki_links_survive_sanitizing() {
+        let html = render_content("See [[Other Page]] <script>alert(1)</script>", DEFAULT_CONTENT_TYPE, "Main");
+        assert!(html.contains("<a href=\"/webs/Main/pages/Other%20Page\""), "{}", html);
+        assert!(html.contains(">Other Page</a>"), "{}", html);
+        assert_harmless(&html);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+// how often buckets that have refilled completely are forgotten
+const CLEANUP_INTERVAL_SECS: u64 = 60;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+// Token buckets per client address, kept separately for reads and
+// writes. A limit of N allows bursts of N requests and refills at N per
+// minute; `None` leaves that kind of request unlimited. At most
+// `capacity` clients are tracked, dropping the least recently seen.
+pub struct RateLimiter {
+    read: Option<u32>,
+    write: Option<u32>,
+    capacity: usize,
+    buckets: HashMap<(IpAddr, bool), Bucket>,
+    cleaned: Instant
+}
+
+impl RateLimiter {
+    pub fn new(read: Option<u32>, write: Option<u32>, capacity: usize) -> RateLimiter {
+        RateLimiter { read, write, capacity, buckets: HashMap::new(), cleaned: Instant::now() }
+    }
+
+    // Take a token for the client, or return how many seconds to wait
+    // until one is available.
+    pub fn check(&mut self, client: IpAddr, write: bool) -> Result<(), u64> {
+        let limit = match if write { self.write } else { self.read } {
+            Some(limit) => limit as f64,
+            None => return Ok(())
+        };
+        let now = Instant::now();
+        if now.duration_since(self.cleaned) >= Duration::from_secs(CLEANUP_INTERVAL_SECS) {
+            self.cleanup(now);
+        }
+        let key = (client, write);
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= self.capacity {
+            self.cleanup(now);
+            if self.buckets.len() >= self.capacity {
+                let oldest = self.buckets.iter().
+                    min_by_key(|&(_, bucket)| bucket.updated).
+                    map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    self.buckets.remove(&oldest);
+                }
+            }
+        }
+
+        let rate = limit / 60.0;
+        let bucket = self.buckets.entry(key).or_insert(Bucket { tokens: limit, updated: now });
+        bucket.tokens = (bucket.tokens + elapsed_secs(bucket.updated, now) * rate).min(limit);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+
+    fn cleanup(&mut self, now: Instant) {
+        let (read, write) = (self.read, self.write);
+        self.buckets.retain(|&(_, is_write), bucket| {
+            let limit = if is_write { write } else { read }.unwrap_or(0) as f64;
+            bucket.tokens + elapsed_secs(bucket.updated, now) * limit / 60.0 < limit
+        });
+        self.cleaned = now;
+    }
+}
+
+fn elapsed_secs(since: Instant, now: Instant) -> f64 {
+    let elapsed = now.duration_since(since);
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
@@ -1,5 +1,6 @@
+use std::str;
 use std::collections::HashMap;
-use regex::Regex;
+use regex::{self, Regex};
 use hyper::{Request, Method};
 
 struct ParamPath {
@@ -24,10 +25,10 @@ impl ParamPath {
                         let part = format!(r"(?P<{}>[^/]+)", name);
                         re.push_str(&part);
                     } else {
-                        re.push_str(part);
+                        re.push_str(&regex::escape(part));
                     }
                 },
-                None => re.push_str(part)
+                None => ()
             };
 
         }
@@ -41,7 +42,9 @@ impl ParamPath {
             Some(caps) => {
                 let map = self.names.iter().fold(HashMap::new(), |mut map, name| {
                     if let Some(m) = caps.name(name) {
-                        map.insert(name.clone(), m.as_str().to_string());
+                        if let Some(value) = decode_param(m.as_str()) {
+                            map.insert(name.clone(), value);
+                        }
                     }
                     map
                 });
@@ -56,47 +59,354 @@ impl ParamPath {
     }
 }
 
+fn percent_decode(raw: &str) -> Option<String> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() ||
+                !(bytes[i + 1] as char).is_digit(16) || !(bytes[i + 2] as char).is_digit(16) {
+                return None;
+            }
+            let hex = match str::from_utf8(&bytes[i + 1..i + 3]) {
+                Ok(hex) => hex,
+                Err(_) => return None
+            };
+            match u8::from_str_radix(hex, 16) {
+                Ok(byte) => decoded.push(byte),
+                Err(_) => return None
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Percent-decode a captured path parameter. Decoded values that could
+// escape their directory (slashes, "." or "..") are rejected.
+pub fn decode_param(raw: &str) -> Option<String> {
+    let value = match percent_decode(raw) {
+        Some(value) => value,
+        None => return None
+    };
+    if value.is_empty() || value == "." || value == ".." ||
+        value.contains('/') || value.contains('\\') {
+        return None;
+    }
+    Some(value)
+}
+
+// Parse the request's query string; pairs that fail to decode are dropped.
+pub fn query_params(request: &Request) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    if let Some(query) = request.query() {
+        for pair in query.split('&') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("").replace('+', " ");
+            let value = parts.next().unwrap_or("").replace('+', " ");
+            if let (Some(key), Some(value)) = (percent_decode(&key), percent_decode(&value)) {
+                if !key.is_empty() {
+                    params.insert(key, value);
+                }
+            }
+        }
+    }
+    params
+}
+
 pub enum Route {
+    Info,
+    Health,
+    Ready,
+    SearchAll,
+    CollectGarbage,
+    ShowConfig,
+    Reindex { web_name: Option<String> },
+    Batch,
+    SetWebReadOnly { web_name: String },
     ListWebs,
     CreateWeb,
+    ShowWeb    { web_name: String },
+    WebIndex   { web_name: String },
+    ExportWeb  { web_name: String },
+    ImportWeb  { web_name: String },
+    CloneWeb   { web_name: String },
+    WebStats   { web_name: String },
+    WebEvents  { web_name: String },
+    BrokenLinks { web_name: String },
+    LinkGraph  { web_name: String },
+    PreviewPage { web_name: String },
     ListPages  { web_name: String },
-    CreatePage { web_name: String },
+    StreamPages { web_name: String },
+    // `page_name` is set when posting to the page's own URL.
+    CreatePage { web_name: String, page_name: Option<String> },
+    BulkCreatePages { web_name: String },
     ShowPage   { web_name: String, page_name: String },
+    PageExists { web_name: String, page_name: String },
+    ShowPageRaw { web_name: String, page_name: String },
+    ExportPage { web_name: String, page_name: String },
     UpdatePage { web_name: String, page_name: String },
+    PatchPage  { web_name: String, page_name: String },
+    CopyPage   { web_name: String, page_name: String },
+    TouchPage  { web_name: String, page_name: String },
+    RepairPage { web_name: String, page_name: String },
+    PageSocket { web_name: String, page_name: String },
+    ValidatePage { web_name: String, page_name: String },
+    ShowPageLock    { web_name: String, page_name: String },
+    LockPage        { web_name: String, page_name: String },
+    UnlockPage      { web_name: String, page_name: String },
     ListAttachments  { web_name: String, page_name: String },
     CreateAttachment { web_name: String, page_name: String },
+    UploadAttachment { web_name: String, page_name: String, attachment_name: String },
+    ShowUploadOffset { web_name: String, page_name: String, attachment_name: String },
     ServeAttachment  { web_name: String, page_name: String, attachment_name: String },
+    ShowAttachmentInfo { web_name: String, page_name: String, attachment_name: String },
+    RenameAttachment { web_name: String, page_name: String, attachment_name: String },
     ListPageVersions { web_name: String, page_name: String },
-    ShowPageVersion  { web_name: String, page_name: String, version_hash: String },
+    ShowPageAudit    { web_name: String, page_name: String },
+    ShowPageHash     { web_name: String, page_name: String },
+    ShowPageLinks    { web_name: String, page_name: String },
+    ListPageAncestors { web_name: String, page_name: String },
+    ListPageChildren  { web_name: String, page_name: String },
+    // `html` is set for the `/html` form, which skips content negotiation.
+    ShowPageVersion  { web_name: String, page_name: String, version_hash: String, html: bool },
+    DiffPageVersion  { web_name: String, page_name: String, version_hash: String },
+    DeletePageVersion { web_name: String, page_name: String, version_hash: String },
+    MethodNotAllowed { allowed: Vec<Method> },
     Invalid
 }
 
 impl<'a> From<&'a Request> for Route {
     fn from(request: &'a Request) -> Route {
+        let path = request.path();
+        match Route::matching(request.method(), path) {
+            Route::Invalid => {
+                let allowed = Route::allowed_methods(path);
+                if allowed.is_empty() {
+                    Route::Invalid
+                } else {
+                    Route::MethodNotAllowed { allowed }
+                }
+            },
+            route => route
+        }
+    }
+}
+
+impl Route {
+    // The web a route writes to, if it writes. Read-only webs refuse these.
+    pub fn written_web(&self) -> Option<&str> {
+        match *self {
+            Route::ImportWeb { ref web_name } |
+                Route::CreatePage { ref web_name, .. } |
+                Route::BulkCreatePages { ref web_name } |
+                Route::UpdatePage { ref web_name, .. } |
+                Route::PatchPage { ref web_name, .. } |
+                Route::TouchPage { ref web_name, .. } |
+                Route::RepairPage { ref web_name, .. } |
+                Route::PageSocket { ref web_name, .. } |
+                Route::CreateAttachment { ref web_name, .. } |
+                Route::UploadAttachment { ref web_name, .. } |
+                Route::RenameAttachment { ref web_name, .. } |
+                Route::DeletePageVersion { ref web_name, .. } => Some(web_name),
+            _ => None
+        }
+    }
+
+    // The web a route works in, if any.
+    pub fn web_name(&self) -> Option<&str> {
+        match *self {
+            Route::SetWebReadOnly { ref web_name } |
+                Route::Reindex { web_name: Some(ref web_name) } |
+                Route::ShowWeb { ref web_name } |
+                Route::WebIndex { ref web_name } |
+                Route::ExportWeb { ref web_name } |
+                Route::ImportWeb { ref web_name } |
+                Route::CloneWeb { ref web_name } |
+                Route::WebStats { ref web_name } |
+                Route::WebEvents { ref web_name } |
+                Route::BrokenLinks { ref web_name } |
+                Route::LinkGraph { ref web_name } |
+                Route::PreviewPage { ref web_name } |
+                Route::ListPages { ref web_name } |
+                Route::StreamPages { ref web_name } |
+                Route::CreatePage { ref web_name, .. } |
+                Route::BulkCreatePages { ref web_name } |
+                Route::ShowPage { ref web_name, .. } |
+                Route::PageExists { ref web_name, .. } |
+                Route::ShowPageRaw { ref web_name, .. } |
+                Route::ExportPage { ref web_name, .. } |
+                Route::UpdatePage { ref web_name, .. } |
+                Route::PatchPage { ref web_name, .. } |
+                Route::CopyPage { ref web_name, .. } |
+                Route::TouchPage { ref web_name, .. } |
+                Route::RepairPage { ref web_name, .. } |
+                Route::PageSocket { ref web_name, .. } |
+                Route::ValidatePage { ref web_name, .. } |
+                Route::ShowPageLock { ref web_name, .. } |
+                Route::LockPage { ref web_name, .. } |
+                Route::UnlockPage { ref web_name, .. } |
+                Route::ListAttachments { ref web_name, .. } |
+                Route::CreateAttachment { ref web_name, .. } |
+                Route::UploadAttachment { ref web_name, .. } |
+                Route::ShowUploadOffset { ref web_name, .. } |
+                Route::ServeAttachment { ref web_name, .. } |
+                Route::ShowAttachmentInfo { ref web_name, .. } |
+                Route::RenameAttachment { ref web_name, .. } |
+                Route::ListPageVersions { ref web_name, .. } |
+                Route::ShowPageAudit { ref web_name, .. } |
+                Route::ShowPageHash { ref web_name, .. } |
+                Route::ShowPageLinks { ref web_name, .. } |
+                Route::ListPageAncestors { ref web_name, .. } |
+                Route::ListPageChildren { ref web_name, .. } |
+                Route::ShowPageVersion { ref web_name, .. } |
+                Route::DiffPageVersion { ref web_name, .. } |
+                Route::DeletePageVersion { ref web_name, .. } => Some(web_name),
+            _ => None
+        }
+    }
+
+    // Methods that have a route for `path`.
+    pub fn allowed_methods(path: &str) -> Vec<Method> {
+        let methods = vec!(
+            Method::Get,
+            Method::Head,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete
+        );
+        methods.into_iter().filter(|method| {
+            match Route::matching(method, path) {
+                Route::Invalid => false,
+                _ => true
+            }
+        }).collect()
+    }
+
+    fn matching(method: &Method, path: &str) -> Route {
         lazy_static! {
+            static ref ROOT_PATH: ParamPath        = ParamPath::new("/");
+            static ref INFO_PATH: ParamPath        = ParamPath::new("/info");
+            static ref HEALTH_PATH: ParamPath      = ParamPath::new("/health");
+            static ref READY_PATH: ParamPath       = ParamPath::new("/ready");
+            static ref SEARCH_PATH: ParamPath      = ParamPath::new("/search");
+            static ref GC_PATH: ParamPath          = ParamPath::new("/admin/gc");
+            static ref CONFIG_PATH: ParamPath      = ParamPath::new("/admin/config");
+            static ref REINDEX_PATH: ParamPath     = ParamPath::new("/admin/reindex");
+            static ref WEB_REINDEX_PATH: ParamPath = ParamPath::new("/admin/webs/:web_name/reindex");
+            static ref BATCH_PATH: ParamPath       = ParamPath::new("/batch");
+            static ref READ_ONLY_PATH: ParamPath   = ParamPath::new("/admin/webs/:web_name/read-only");
             static ref WEBS_PATH: ParamPath        = ParamPath::new("/webs");
-            //static ref WEB_PATH:  ParamPath      = ParamPath::new("/webs/:web_name");
+            static ref WEB_PATH:  ParamPath        = ParamPath::new("/webs/:web_name");
+            static ref WEB_INDEX_PATH: ParamPath   = ParamPath::new("/webs/:web_name/");
+            static ref EXPORT_PATH: ParamPath      = ParamPath::new("/webs/:web_name/export");
+            static ref STATS_PATH: ParamPath       = ParamPath::new("/webs/:web_name/stats");
+            static ref EVENTS_PATH: ParamPath      = ParamPath::new("/webs/:web_name/events");
+            static ref BROKEN_LINKS_PATH: ParamPath = ParamPath::new("/webs/:web_name/links/broken");
+            static ref GRAPH_PATH: ParamPath       = ParamPath::new("/webs/:web_name/graph");
+            static ref IMPORT_PATH: ParamPath      = ParamPath::new("/webs/:web_name/import");
+            static ref CLONE_PATH: ParamPath       = ParamPath::new("/webs/:web_name/clone");
+            static ref PREVIEW_PATH: ParamPath     = ParamPath::new("/webs/:web_name/preview");
             static ref PAGES_PATH: ParamPath       = ParamPath::new("/webs/:web_name/pages");
+            static ref NDJSON_PATH: ParamPath      = ParamPath::new("/webs/:web_name/pages.ndjson");
+            static ref BULK_PATH: ParamPath        = ParamPath::new("/webs/:web_name/pages/bulk");
             static ref PAGE_PATH: ParamPath        = ParamPath::new("/webs/:web_name/pages/:page_name");
+            static ref RAW_PATH: ParamPath         = ParamPath::new("/webs/:web_name/pages/:page_name/raw");
+            static ref PAGE_EXPORT_PATH: ParamPath = ParamPath::new("/webs/:web_name/pages/:page_name/export.html");
+            static ref LOCK_PATH: ParamPath        = ParamPath::new("/webs/:web_name/pages/:page_name/lock");
+            static ref REPAIR_PATH: ParamPath      = ParamPath::new("/webs/:web_name/pages/:page_name/repair");
+            static ref TOUCH_PATH: ParamPath       = ParamPath::new("/webs/:web_name/pages/:page_name/touch");
+            static ref COPY_PATH: ParamPath        = ParamPath::new("/webs/:web_name/pages/:page_name/copy");
+            static ref VALIDATE_PATH: ParamPath    = ParamPath::new("/webs/:web_name/pages/:page_name/validate");
             static ref ATTACHMENTS_PATH: ParamPath = ParamPath::new("/webs/:web_name/pages/:page_name/attachments");
             static ref ATTACHMENT_PATH: ParamPath  = ParamPath::new("/webs/:web_name/pages/:page_name/attachments/:attachment_name");
+            static ref ATTACHMENT_INFO_PATH: ParamPath = ParamPath::new("/webs/:web_name/pages/:page_name/attachments/:attachment_name/info");
+            static ref RENAME_PATH: ParamPath      = ParamPath::new("/webs/:web_name/pages/:page_name/attachments/:attachment_name/rename");
             static ref VERSIONS_PATH: ParamPath    = ParamPath::new("/webs/:web_name/pages/:page_name/versions");
+            static ref AUDIT_PATH: ParamPath       = ParamPath::new("/webs/:web_name/pages/:page_name/audit");
+            static ref SOCKET_PATH: ParamPath      = ParamPath::new("/webs/:web_name/pages/:page_name/ws");
+            static ref HASH_PATH: ParamPath        = ParamPath::new("/webs/:web_name/pages/:page_name/hash");
+            static ref PAGE_LINKS_PATH: ParamPath  = ParamPath::new("/webs/:web_name/pages/:page_name/links");
+            static ref ANCESTORS_PATH: ParamPath   = ParamPath::new("/webs/:web_name/pages/:page_name/ancestors");
+            static ref CHILDREN_PATH: ParamPath    = ParamPath::new("/webs/:web_name/pages/:page_name/children");
             static ref VERSION_PATH: ParamPath     = ParamPath::new("/webs/:web_name/pages/:page_name/versions/:version_hash");
+            static ref VERSION_HTML_PATH: ParamPath = ParamPath::new("/webs/:web_name/pages/:page_name/versions/:version_hash/html");
+            static ref VERSION_DIFF_PATH: ParamPath = ParamPath::new("/webs/:web_name/pages/:page_name/versions/:version_hash/diff");
         }
-        let path = request.path();
-        match request.method() {
+        match method {
             &Method::Get => {
-                if let Some(_) = WEBS_PATH.test(&path) {
+                if let Some(_) = ROOT_PATH.test(&path) {
+                    Route::Info
+
+                } else if let Some(_) = INFO_PATH.test(&path) {
+                    Route::Info
+
+                } else if let Some(_) = CONFIG_PATH.test(&path) {
+                    Route::ShowConfig
+
+                } else if let Some(_) = HEALTH_PATH.test(&path) {
+                    Route::Health
+
+                } else if let Some(_) = READY_PATH.test(&path) {
+                    Route::Ready
+
+                } else if let Some(_) = SEARCH_PATH.test(&path) {
+                    Route::SearchAll
+
+                } else if let Some(_) = WEBS_PATH.test(&path) {
                     Route::ListWebs
 
+                } else if let Some(mut params) = WEB_PATH.test(&path) {
+                    Route::ShowWeb { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = WEB_INDEX_PATH.test(&path) {
+                    Route::WebIndex { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = EXPORT_PATH.test(&path) {
+                    Route::ExportWeb { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = STATS_PATH.test(&path) {
+                    Route::WebStats { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = EVENTS_PATH.test(&path) {
+                    Route::WebEvents { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = BROKEN_LINKS_PATH.test(&path) {
+                    Route::BrokenLinks { web_name: params.remove("web_name").unwrap() }
+                } else if let Some(mut params) = GRAPH_PATH.test(&path) {
+                    Route::LinkGraph { web_name: params.remove("web_name").unwrap() }
+
                 } else if let Some(mut params) = PAGES_PATH.test(&path) {
                     Route::ListPages { web_name: params.remove("web_name").unwrap() }
 
+                } else if let Some(mut params) = NDJSON_PATH.test(&path) {
+                    Route::StreamPages { web_name: params.remove("web_name").unwrap() }
+
                 } else if let Some(mut params) = PAGE_PATH.test(&path) {
                     Route::ShowPage {
                         web_name:  params.remove("web_name").unwrap(),
                         page_name: params.remove("page_name").unwrap()
                     }
+                } else if let Some(mut params) = LOCK_PATH.test(&path) {
+                    Route::ShowPageLock {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = RAW_PATH.test(&path) {
+                    Route::ShowPageRaw {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = PAGE_EXPORT_PATH.test(&path) {
+                    Route::ExportPage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
                 } else if let Some(mut params) = ATTACHMENTS_PATH.test(&path) {
                     Route::ListAttachments {
                         web_name:  params.remove("web_name").unwrap(),
@@ -108,13 +418,63 @@ impl<'a> From<&'a Request> for Route {
                         page_name: params.remove("page_name").unwrap(),
                         attachment_name: params.remove("attachment_name").unwrap()
                     }
+                } else if let Some(mut params) = ATTACHMENT_INFO_PATH.test(&path) {
+                    Route::ShowAttachmentInfo {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        attachment_name: params.remove("attachment_name").unwrap()
+                    }
                 } else if let Some(mut params) = VERSIONS_PATH.test(&path) {
                     Route::ListPageVersions {
                         web_name:  params.remove("web_name").unwrap(),
                         page_name: params.remove("page_name").unwrap()
                     }
+                } else if let Some(mut params) = HASH_PATH.test(&path) {
+                    Route::ShowPageHash {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = PAGE_LINKS_PATH.test(&path) {
+                    Route::ShowPageLinks {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = AUDIT_PATH.test(&path) {
+                    Route::ShowPageAudit {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = SOCKET_PATH.test(&path) {
+                    Route::PageSocket {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = ANCESTORS_PATH.test(&path) {
+                    Route::ListPageAncestors {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = CHILDREN_PATH.test(&path) {
+                    Route::ListPageChildren {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
                 } else if let Some(mut params) = VERSION_PATH.test(&path) {
                     Route::ShowPageVersion {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        version_hash: params.remove("version_hash").unwrap(),
+                        html: false
+                    }
+                } else if let Some(mut params) = VERSION_HTML_PATH.test(&path) {
+                    Route::ShowPageVersion {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        version_hash: params.remove("version_hash").unwrap(),
+                        html: true
+                    }
+                } else if let Some(mut params) = VERSION_DIFF_PATH.test(&path) {
+                    Route::DiffPageVersion {
                         web_name:  params.remove("web_name").unwrap(),
                         page_name: params.remove("page_name").unwrap(),
                         version_hash: params.remove("version_hash").unwrap()
@@ -124,17 +484,78 @@ impl<'a> From<&'a Request> for Route {
                 }
             },
             &Method::Post => {
-                if let Some(_) = WEBS_PATH.test(&path) {
+                if let Some(_) = GC_PATH.test(&path) {
+                    Route::CollectGarbage
+
+                } else if let Some(_) = REINDEX_PATH.test(&path) {
+                    Route::Reindex { web_name: None }
+
+                } else if let Some(mut params) = WEB_REINDEX_PATH.test(&path) {
+                    Route::Reindex { web_name: Some(params.remove("web_name").unwrap()) }
+
+                } else if let Some(_) = BATCH_PATH.test(&path) {
+                    Route::Batch
+
+                } else if let Some(_) = WEBS_PATH.test(&path) {
                     Route::CreateWeb
 
+                } else if let Some(mut params) = IMPORT_PATH.test(&path) {
+                    Route::ImportWeb { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = CLONE_PATH.test(&path) {
+                    Route::CloneWeb { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = PREVIEW_PATH.test(&path) {
+                    Route::PreviewPage { web_name: params.remove("web_name").unwrap() }
+
                 } else if let Some(mut params) = PAGES_PATH.test(&path) {
-                    Route::CreatePage { web_name: params.remove("web_name").unwrap() }
+                    Route::CreatePage { web_name: params.remove("web_name").unwrap(), page_name: None }
 
+                } else if let Some(mut params) = BULK_PATH.test(&path) {
+                    Route::BulkCreatePages { web_name: params.remove("web_name").unwrap() }
+
+                } else if let Some(mut params) = PAGE_PATH.test(&path) {
+                    Route::CreatePage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: Some(params.remove("page_name").unwrap())
+                    }
+
+                } else if let Some(mut params) = LOCK_PATH.test(&path) {
+                    Route::LockPage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = VALIDATE_PATH.test(&path) {
+                    Route::ValidatePage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = COPY_PATH.test(&path) {
+                    Route::CopyPage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = TOUCH_PATH.test(&path) {
+                    Route::TouchPage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = REPAIR_PATH.test(&path) {
+                    Route::RepairPage {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
                 } else if let Some(mut params) = ATTACHMENTS_PATH.test(&path) {
                     Route::CreateAttachment {
                         web_name:  params.remove("web_name").unwrap(),
                         page_name: params.remove("page_name").unwrap()
                     }
+                } else if let Some(mut params) = RENAME_PATH.test(&path) {
+                    Route::RenameAttachment {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        attachment_name: params.remove("attachment_name").unwrap()
+                    }
                 } else {
                     Route::Invalid
                 }
@@ -146,6 +567,62 @@ impl<'a> From<&'a Request> for Route {
                         page_name: params.remove("page_name").unwrap()
                     }
 
+                } else if let Some(mut params) = ATTACHMENT_PATH.test(&path) {
+                    Route::UploadAttachment {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        attachment_name: params.remove("attachment_name").unwrap()
+                    }
+
+                } else if let Some(mut params) = READ_ONLY_PATH.test(&path) {
+                    Route::SetWebReadOnly { web_name: params.remove("web_name").unwrap() }
+
+                } else {
+                    Route::Invalid
+                }
+            },
+            &Method::Head => {
+                if let Some(mut params) = PAGE_PATH.test(&path) {
+                    Route::PageExists {
+                        web_name: params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+
+                } else if let Some(mut params) = ATTACHMENT_PATH.test(&path) {
+                    Route::ShowUploadOffset {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        attachment_name: params.remove("attachment_name").unwrap()
+                    }
+
+                } else {
+                    Route::Invalid
+                }
+            },
+            &Method::Patch => {
+                if let Some(mut params) = PAGE_PATH.test(&path) {
+                    Route::PatchPage {
+                        web_name: params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+
+                } else {
+                    Route::Invalid
+                }
+            },
+            &Method::Delete => {
+                if let Some(mut params) = LOCK_PATH.test(&path) {
+                    Route::UnlockPage {
+                        web_name: params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap()
+                    }
+                } else if let Some(mut params) = VERSION_PATH.test(&path) {
+                    Route::DeletePageVersion {
+                        web_name:  params.remove("web_name").unwrap(),
+                        page_name: params.remove("page_name").unwrap(),
+                        version_hash: params.remove("version_hash").unwrap()
+                    }
+
                 } else {
                     Route::Invalid
                 }
@@ -154,3 +631,45 @@ impl<'a> From<&'a Request> for Route {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Method, Request};
+    use super::*;
+
+    fn route(method: Method, path: &str) -> Route {
+        Route::from(&Request::new(method, path.parse().unwrap()))
+    }
+
+    #[test]
+    fn web_path_shows_the_web() {
+        match route(Method::Get, "/webs/Main") {
+            Route::ShowWeb { web_name } => assert_eq!(web_name, "Main"),
+            _ => panic!("/webs/Main is not ShowWeb")
+        }
+    }
+
+    #[test]
+    fn delete_on_webs_is_not_allowed() {
+        match route(Method::Delete, "/webs") {
+            Route::MethodNotAllowed { allowed } => assert_eq!(allowed, [Method::Get, Method::Post]),
+            _ => panic!("DELETE /webs is not MethodNotAllowed")
+        }
+    }
+
+    // POST to a page's own URL creates it, so DELETE is the method a page
+    // path turns away.
+    #[test]
+    fn delete_on_a_page_is_not_allowed() {
+        match route(Method::Post, "/webs/Main/pages/Home") {
+            Route::CreatePage { page_name: Some(ref page_name), .. } => assert_eq!(page_name, "Home"),
+            _ => panic!("POST on a page is not CreatePage")
+        }
+        match route(Method::Delete, "/webs/Main/pages/Home") {
+            Route::MethodNotAllowed { allowed } => {
+                assert_eq!(allowed, [Method::Get, Method::Head, Method::Post, Method::Put, Method::Patch]);
+            },
+            _ => panic!("DELETE on a page is not MethodNotAllowed")
+        }
+    }
+}
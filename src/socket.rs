@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::mpsc;
+use hyper::{HttpVersion, Request};
+use serde_json::{self, Value};
+use tungstenite::{self, Message, WebSocket};
+use tungstenite::handshake::derive_accept_key;
+
+use page::PageDetail;
+
+// messages buffered per client before it is considered too slow to keep up
+const CLIENT_BUFFER: usize = 64;
+
+// how long a room must go without edits before its draft is saved
+pub const SAVE_DELAY_MS: u64 = 2000;
+
+// The Sec-WebSocket-Accept value for a request asking to switch to
+// WebSocket, or `None` if it is not one. Upgrades only exist in HTTP/1.1.
+pub fn accept_key(request: &Request) -> Option<String> {
+    if request.version() != HttpVersion::Http11 {
+        return None;
+    }
+    let headers = request.headers();
+    let has_token = |name: &str, token: &str| {
+        headers.get_raw(name).map_or(false, |raw| raw.iter().any(|line| {
+            String::from_utf8_lossy(line).split(',').any(|value| value.trim().eq_ignore_ascii_case(token))
+        }))
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") || !has_token("Sec-WebSocket-Version", "13") {
+        return None;
+    }
+    headers.get_raw("Sec-WebSocket-Key").
+        and_then(|raw| raw.one()).
+        map(|key| derive_accept_key(key))
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Outgoing<'a> {
+    Welcome { client: u64, page: &'a PageDetail },
+    Join { client: u64 },
+    Leave { client: u64 },
+    Patch { client: u64, patch: &'a Value },
+    Saved { hash: &'a str },
+    Error { message: &'a str }
+}
+
+struct Client {
+    id: u64,
+    sender: mpsc::Sender<String>
+}
+
+// `draft` holds edits that have not been saved yet, and `generation`
+// identifies the latest of them.
+struct Room {
+    clients: Vec<Client>,
+    draft: Option<PageDetail>,
+    generation: u64
+}
+
+impl Room {
+    // Every client but `except` gets the message. A client whose
+    // connection has gone away, or whose buffer is full, is dropped.
+    fn broadcast(&mut self, message: &Outgoing, except: Option<u64>) {
+        let message = serde_json::to_string(message).unwrap();
+        self.clients.retain_mut(|client| {
+            if Some(client.id) == except {
+                return true;
+            }
+            client.sender.try_send(message.clone()).is_ok()
+        });
+    }
+}
+
+// Editing rooms, one per page with clients connected. Client ids and
+// draft generations come from the same counter, so neither repeats.
+pub struct Rooms {
+    rooms: HashMap<(String, String), Room>,
+    counter: u64
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms { rooms: HashMap::new(), counter: 0 }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    // Add a client to the page's room. It is welcomed with the page as
+    // the room has it, which is `stored` unless there is a draft.
+    pub fn join(&mut self, web_name: &str, page_name: &str, stored: PageDetail) -> (u64, mpsc::Receiver<String>) {
+        let id = self.next();
+        let (mut sender, receiver) = mpsc::channel(CLIENT_BUFFER);
+        let room = self.rooms.entry((web_name.to_string(), page_name.to_string())).
+            or_insert_with(|| Room { clients: Vec::new(), draft: None, generation: 0 });
+        room.broadcast(&Outgoing::Join { client: id }, None);
+        {
+            let page = room.draft.as_ref().unwrap_or(&stored);
+            let _ = sender.try_send(serde_json::to_string(&Outgoing::Welcome { client: id, page }).unwrap());
+        }
+        room.clients.push(Client { id, sender });
+        (id, receiver)
+    }
+
+    // Remove a client, closing the room when it was the last one. The
+    // unsaved draft is handed back then, so it can be saved right away.
+    pub fn leave(&mut self, web_name: &str, page_name: &str, id: u64) -> Option<PageDetail> {
+        let key = (web_name.to_string(), page_name.to_string());
+        let empty = match self.rooms.get_mut(&key) {
+            Some(room) => {
+                room.clients.retain(|client| client.id != id);
+                room.broadcast(&Outgoing::Leave { client: id }, None);
+                room.clients.is_empty()
+            },
+            None => false
+        };
+        if empty {
+            self.rooms.remove(&key).and_then(|room| room.draft)
+        } else {
+            None
+        }
+    }
+
+    pub fn draft(&self, web_name: &str, page_name: &str) -> Option<PageDetail> {
+        self.rooms.get(&(web_name.to_string(), page_name.to_string())).
+            and_then(|room| room.draft.clone())
+    }
+
+    // Replace the draft with an edited one and relay the patch that made
+    // it to the other clients. Returns the draft's generation.
+    pub fn edit(&mut self, web_name: &str, page_name: &str, id: u64, draft: PageDetail, patch: &Value) -> Option<u64> {
+        let generation = self.next();
+        let room = self.rooms.get_mut(&(web_name.to_string(), page_name.to_string()))?;
+        room.draft = Some(draft);
+        room.generation = generation;
+        room.broadcast(&Outgoing::Patch { client: id, patch }, Some(id));
+        Some(generation)
+    }
+
+    // The draft, if nothing has been edited since `generation`.
+    pub fn take_draft(&mut self, web_name: &str, page_name: &str, generation: u64) -> Option<PageDetail> {
+        match self.rooms.get_mut(&(web_name.to_string(), page_name.to_string())) {
+            Some(ref mut room) if room.generation == generation => room.draft.take(),
+            _ => None
+        }
+    }
+
+    pub fn saved(&mut self, web_name: &str, page_name: &str, hash: &str) {
+        if let Some(room) = self.rooms.get_mut(&(web_name.to_string(), page_name.to_string())) {
+            room.broadcast(&Outgoing::Saved { hash }, None);
+        }
+    }
+
+    // Report a problem to one client, or to all of them with no `id`.
+    pub fn error(&mut self, web_name: &str, page_name: &str, id: Option<u64>, message: &str) {
+        if let Some(room) = self.rooms.get_mut(&(web_name.to_string(), page_name.to_string())) {
+            let message = serde_json::to_string(&Outgoing::Error { message }).unwrap();
+            for client in room.clients.iter_mut() {
+                if id.map_or(true, |id| id == client.id) {
+                    let _ = client.sender.try_send(message.clone());
+                }
+            }
+        }
+    }
+}
+
+fn would_block(err: &tungstenite::Error) -> bool {
+    match *err {
+        tungstenite::Error::Io(ref err) => err.kind() == io::ErrorKind::WouldBlock,
+        _ => false
+    }
+}
+
+// One client's connection: messages from its room are written out, and
+// each text message it sends is passed to `on_text`. Finishes when the
+// connection closes or fails, or the room drops the client.
+pub struct Session<T, F> {
+    socket: WebSocket<T>,
+    receiver: mpsc::Receiver<String>,
+    on_text: F
+}
+
+impl<T: Read + Write, F: FnMut(String)> Session<T, F> {
+    // `read_buf` holds anything the client sent after its upgrade request.
+    pub fn new(io: T, read_buf: Vec<u8>, receiver: mpsc::Receiver<String>, on_text: F) -> Session<T, F> {
+        let socket = WebSocket::from_partially_read(io, read_buf, tungstenite::protocol::Role::Server, None);
+        Session { socket, receiver, on_text }
+    }
+}
+
+impl<T: Read + Write, F: FnMut(String)> Future for Session<T, F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            match self.receiver.poll() {
+                Ok(Async::Ready(Some(text))) => {
+                    match self.socket.write(Message::Text(text)) {
+                        Err(ref err) if !would_block(err) => return Ok(Async::Ready(())),
+                        _ => ()
+                    }
+                },
+                Ok(Async::NotReady) => break,
+                _ => return Ok(Async::Ready(()))
+            }
+        }
+        match self.socket.flush() {
+            Err(ref err) if !would_block(err) => return Ok(Async::Ready(())),
+            _ => ()
+        }
+
+        // pings and closes are answered by the socket itself
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => (self.on_text)(text),
+                Ok(_) => (),
+                Err(ref err) if would_block(err) => return Ok(Async::NotReady),
+                Err(_) => return Ok(Async::Ready(()))
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::io::{self, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use serde_json;
+use serde_yaml;
+use uuid::Uuid;
+
+use page::{write_atomic, sync_parent, default_content_type, PageDetail, PageError, ATTACHMENTS_DIRECTORY, DEFAULT_CONTENT_TYPE};
+use attachment::BLOBS_DIRECTORY;
+
+pub const JSON_FILENAME: &'static str = "page.json";
+pub const MARKDOWN_FILENAME: &'static str = "page.md";
+
+const FRONT_MATTER_FENCE: &'static str = "---\n";
+
+const RETRY_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF_MS: u64 = 10;
+
+// Errors a networked filesystem may give for an operation that will
+// succeed if it is simply tried again.
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        _ => false
+    }
+}
+
+// Run `op` up to `attempts` times, doubling the pause after each
+// transient failure. Any other error is returned at once.
+fn retry<T, F>(attempts: u32, mut op: F) -> io::Result<T>
+    where F: FnMut() -> io::Result<T>
+{
+    let mut backoff = Duration::from_millis(RETRY_BACKOFF_MS);
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(ref err) if attempt < attempts && is_transient(err) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+// How a page detail is laid out on disk. The same encoding is used for
+// the live page file and for its version files.
+pub trait PageStore {
+    fn file_name(&self) -> &'static str;
+    fn version_extension(&self) -> &'static str;
+    fn encode(&self, detail: &PageDetail) -> Result<Vec<u8>, PageError>;
+    fn decode(&self, data: &[u8]) -> Result<PageDetail, PageError>;
+}
+
+pub struct JsonStore;
+
+impl PageStore for JsonStore {
+    fn file_name(&self) -> &'static str {
+        JSON_FILENAME
+    }
+
+    fn version_extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, detail: &PageDetail) -> Result<Vec<u8>, PageError> {
+        let data = serde_json::to_string_pretty(detail)?;
+        Ok(data.into_bytes())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<PageDetail, PageError> {
+        let detail = serde_json::from_slice(data)?;
+        Ok(detail)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct FrontMatter {
+    name: String,
+    title: String,
+    #[serde(default)]
+    parent: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default = "default_content_type", skip_serializing_if = "is_markdown")]
+    content_type: String
+}
+
+fn is_markdown(content_type: &String) -> bool {
+    content_type == DEFAULT_CONTENT_TYPE
+}
+
+// A YAML front-matter block followed by the Markdown content, verbatim.
+pub struct MarkdownStore;
+
+impl PageStore for MarkdownStore {
+    fn file_name(&self) -> &'static str {
+        MARKDOWN_FILENAME
+    }
+
+    fn version_extension(&self) -> &'static str {
+        "md"
+    }
+
+    fn encode(&self, detail: &PageDetail) -> Result<Vec<u8>, PageError> {
+        let front_matter = FrontMatter {
+            name: detail.name.clone(),
+            title: detail.title.clone(),
+            parent: detail.parent().to_string(),
+            tags: detail.tags().to_vec(),
+            content_type: detail.content_type().to_string()
+        };
+        let yaml = serde_yaml::to_string(&front_matter).
+            map_err(|err| PageError::InvalidFormat(err.to_string()))?;
+        let yaml = yaml.trim_start_matches(FRONT_MATTER_FENCE).trim_end();
+        let data = format!("{}{}\n{}{}", FRONT_MATTER_FENCE, yaml, FRONT_MATTER_FENCE, detail.content());
+        Ok(data.into_bytes())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<PageDetail, PageError> {
+        let data = String::from_utf8(data.to_vec()).map_err(|_| PageError::Utf8Error)?;
+        if !data.starts_with(FRONT_MATTER_FENCE) {
+            return Err(PageError::InvalidFormat("missing front matter".to_string()));
+        }
+        let rest = &data[FRONT_MATTER_FENCE.len()..];
+        let end = match rest.find(&format!("\n{}", FRONT_MATTER_FENCE)) {
+            Some(end) => end,
+            None => return Err(PageError::InvalidFormat("unterminated front matter".to_string()))
+        };
+        let front_matter: FrontMatter = serde_yaml::from_str(&rest[..end]).
+            map_err(|err| PageError::InvalidFormat(err.to_string()))?;
+        let content = &rest[end + 1 + FRONT_MATTER_FENCE.len()..];
+        let mut detail = PageDetail::new(
+            front_matter.name,
+            front_matter.title,
+            content.to_string(),
+            front_matter.parent,
+            front_matter.tags
+        );
+        detail.set_content_type(&front_matter.content_type);
+        Ok(detail)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StorageFormat {
+    Json,
+    Markdown
+}
+
+impl StorageFormat {
+    pub fn parse(name: &str) -> Option<StorageFormat> {
+        match name {
+            "json" => Some(StorageFormat::Json),
+            "markdown" => Some(StorageFormat::Markdown),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StorageFormat::Json => "json",
+            StorageFormat::Markdown => "markdown"
+        }
+    }
+}
+
+// On-disk layout options shared by every web and page. With an
+// `attachments_root`, attachments live in `<root>/<web>/<page>/` and blobs
+// in `<root>/<web>/blobs/` instead of inside the web directory. `durable`
+// fsyncs every write and new directory before it is reported done, and
+// `retry_writes` retries them after transient errors. Unless
+// `allow_symlinks` is set, webs and pages that are symbolic links are
+// refused, since they could lead outside the data directory.
+#[derive(Clone, Debug)]
+pub struct Storage {
+    pub format: StorageFormat,
+    pub attachments_root: Option<PathBuf>,
+    pub durable: bool,
+    pub retry_writes: bool,
+    pub allow_symlinks: bool
+}
+
+impl Storage {
+    pub fn new() -> Storage {
+        Storage {
+            format: StorageFormat::Json,
+            attachments_root: None,
+            durable: false,
+            retry_writes: false,
+            allow_symlinks: false
+        }
+    }
+
+    // Looks at `path` itself rather than what it points to; a path that
+    // does not exist is not a link.
+    pub fn refuses_link(&self, path: &Path) -> bool {
+        !self.allow_symlinks && fs::symlink_metadata(path).map_or(false, |metadata| metadata.file_type().is_symlink())
+    }
+
+    fn attempts(&self) -> u32 {
+        if self.retry_writes { RETRY_ATTEMPTS } else { 1 }
+    }
+
+    pub fn write_file(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        retry(self.attempts(), || write_atomic(path, data, self.durable))
+    }
+
+    // Write a file that must not exist yet, returning whether this call
+    // created it. The data is linked into place from a temporary file of
+    // its own, so of several racing callers exactly one succeeds and none
+    // sees the file half written.
+    pub fn create_file(&self, path: &Path, data: &[u8]) -> io::Result<bool> {
+        retry(self.attempts(), || self.link_new_file(path, data))
+    }
+
+    fn link_new_file(&self, path: &Path, data: &[u8]) -> io::Result<bool> {
+        let tmp_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!(".{}.{}.tmp", name, Uuid::new_v4()),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))
+        };
+        let tmp_path = path.with_file_name(tmp_name);
+        let result = File::create(&tmp_path).
+            and_then(|mut file| {
+                file.write_all(data)?;
+                if self.durable {
+                    file.sync_all()?;
+                }
+                Ok(())
+            }).
+            and_then(|_| fs::hard_link(&tmp_path, path));
+        let _ = fs::remove_file(&tmp_path);
+        match result {
+            Ok(_) => {
+                if self.durable {
+                    sync_parent(path)?;
+                }
+                Ok(true)
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err)
+        }
+    }
+
+    // Like `fs::create_dir`, so an existing directory is still an error.
+    pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+        retry(self.attempts(), || fs::create_dir(path))?;
+        if self.durable {
+            sync_parent(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        match self.create_dir(path) {
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+            result => result
+        }
+    }
+
+    fn relocated(root: &Path, path: &Path) -> PathBuf {
+        let web_path = path.parent().unwrap();
+        root.join(web_path.file_name().unwrap()).join(path.file_name().unwrap())
+    }
+
+    pub fn attachments_path(&self, page_path: &Path) -> PathBuf {
+        match self.attachments_root {
+            Some(ref root) => Storage::relocated(root, page_path),
+            None => page_path.join(ATTACHMENTS_DIRECTORY)
+        }
+    }
+
+    // Blobs are shared by every page in the web.
+    pub fn blobs_path(&self, web_path: &Path) -> PathBuf {
+        let blobs_path = web_path.join(BLOBS_DIRECTORY);
+        match self.attachments_root {
+            Some(ref root) => Storage::relocated(root, &blobs_path),
+            None => blobs_path
+        }
+    }
+
+    // Where an archive entry, given relative to the web directory, is
+    // unpacked. Archives always use the in-web layout.
+    pub fn unpack_path(&self, web_path: &Path, relative: &Path) -> PathBuf {
+        let parts: Vec<&Path> = relative.iter().map(Path::new).collect();
+        if parts.len() >= 1 && parts[0] == Path::new(BLOBS_DIRECTORY) {
+            return parts[1..].iter().fold(self.blobs_path(web_path), |path, part| path.join(part));
+        }
+        if parts.len() >= 2 && parts[1] == Path::new(ATTACHMENTS_DIRECTORY) {
+            let attachments_path = self.attachments_path(&web_path.join(parts[0]));
+            return parts[2..].iter().fold(attachments_path, |path, part| path.join(part));
+        }
+        web_path.join(relative)
+    }
+
+    pub fn store(&self) -> &'static dyn PageStore {
+        match self.format {
+            StorageFormat::Json => &JsonStore,
+            StorageFormat::Markdown => &MarkdownStore
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use super::retry;
+
+    // An operation that fails with `kind` for its first `failures` calls,
+    // and a count of how often it was called.
+    fn failing(failures: u32, kind: io::ErrorKind) -> (impl FnMut() -> io::Result<u32>, Rc<Cell<u32>>) {
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let op = move || {
+            counter.set(counter.get() + 1);
+            if counter.get() <= failures {
+                Err(io::Error::new(kind, "simulated failure"))
+            } else {
+                Ok(counter.get())
+            }
+        };
+        (op, calls)
+    }
+
+    #[test]
+    fn retry_succeeds_after_transient_failures() {
+        let (op, calls) = failing(3, io::ErrorKind::Interrupted);
+        assert_eq!(retry(5, op).unwrap(), 4);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn retry_stops_at_the_attempt_bound() {
+        let (op, calls) = failing(10, io::ErrorKind::Interrupted);
+        let err = retry(3, op).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up_at_once_on_other_errors() {
+        let (op, calls) = failing(1, io::ErrorKind::PermissionDenied);
+        let err = retry(5, op).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,28 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+// A fresh directory under the system temp directory, removed again
+// when dropped.
+pub struct ScratchDir {
+    path: PathBuf
+}
+
+impl ScratchDir {
+    pub fn new() -> ScratchDir {
+        let path = env::temp_dir().join(format!("biowiki-test-{}", Uuid::new_v4()));
+        fs::create_dir(&path).unwrap();
+        ScratchDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
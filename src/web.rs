@@ -1,17 +1,44 @@
 use std::{io, error, fmt};
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::convert::From;
-use std::fs;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fs::{self, File};
 use serde_json;
+use tar;
 
 use page::*;
+use store::{Storage, JSON_FILENAME, MARKDOWN_FILENAME};
+use attachment::{read_pointer, total_file_name, upload_part_name, BLOBS_DIRECTORY};
+use links::wiki_links;
+
+pub const WEB_FILENAME: &'static str = "web.json";
+const SCAFFOLD_WEB: &'static str = "Home";
+const SCAFFOLD_PAGE: &'static str = "WebHome";
+const DEFAULT_INDEX_PAGE: &'static str = "WebHome";
+
+// Files younger than this are never collected, so a write that has
+// created a blob or page directory but not yet its pointer or detail
+// file is left alone.
+const GC_GRACE_PERIOD_SECS: u64 = 60 * 60;
+
+// Suggestions are at most this many edits away, fewer for short names.
+// Names longer than SUGGEST_MAX_LENGTH are never compared, which bounds
+// the work per candidate.
+const SUGGEST_MAX_DISTANCE: usize = 3;
+const SUGGEST_MAX_LENGTH: usize = 64;
 
 #[derive(Debug)]
 pub enum WebError {
     NotFound,
     IoError(io::Error),
     JsonError(serde_json::error::Error),
-    OverwriteError
+    OverwriteError,
+    InvalidName,
+    InvalidArchive,
+    Symlink,
+    PageError(PageError)
 }
 
 impl From<serde_json::error::Error> for WebError {
@@ -27,6 +54,10 @@ impl error::Error for WebError {
             &WebError::IoError(ref err) => err.description(),
             &WebError::JsonError(ref err) => err.description(),
             &WebError::OverwriteError => "web directory already exists",
+            &WebError::InvalidName => "web name is not valid",
+            &WebError::InvalidArchive => "web archive contains an invalid entry",
+            &WebError::Symlink => "web metadata is a symbolic link",
+            &WebError::PageError(ref err) => err.description(),
         }
     }
 }
@@ -38,10 +69,20 @@ impl fmt::Display for WebError {
             &WebError::IoError(ref err) => write!(f, "WebError::IoError({})", err),
             &WebError::JsonError(ref err) => write!(f, "WebError::JsonError({})", err),
             &WebError::OverwriteError => write!(f, "WebError::OverwriteError"),
+            &WebError::InvalidName => write!(f, "WebError::InvalidName"),
+            &WebError::InvalidArchive => write!(f, "WebError::InvalidArchive"),
+            &WebError::Symlink => write!(f, "WebError::Symlink"),
+            &WebError::PageError(ref err) => write!(f, "WebError::PageError({})", err),
         }
     }
 }
 
+impl From<PageError> for WebError {
+    fn from(err: PageError) -> WebError {
+        WebError::PageError(err)
+    }
+}
+
 impl From<io::Error> for WebError {
     fn from(err: io::Error) -> WebError {
         match err.kind() {
@@ -54,17 +95,18 @@ impl From<io::Error> for WebError {
 #[derive(Debug)]
 pub struct Web {
     pub name: String,
-    pub path: PathBuf
+    pub path: PathBuf,
+    pub storage: Storage
 }
 
 impl Web {
     pub fn list_pages(&self) -> Result<Vec<PageStub>, WebError> {
-        let stubs = fs::read_dir(&self.path)?.filter(|entry| {
+        let mut stubs: Vec<PageStub> = fs::read_dir(&self.path)?.filter(|entry| {
             match entry {
                 &Err(_) => false,
                 &Ok(ref entry) => {
                     let path = entry.path();
-                    if !path.is_dir() {
+                    if !path.is_dir() || entry.file_name() == BLOBS_DIRECTORY || self.storage.refuses_link(&path) {
                         return false;
                     }
                     let s = path.to_str();
@@ -73,79 +115,1195 @@ impl Web {
             }
         }).map(|entry| {
             let name = entry.unwrap().path().file_name().unwrap().to_str().unwrap().to_string();
-            PageStub { name }
+            PageStub { name, version_count: None, title: None, detail: None }
         }).collect();
+        stubs.sort_by(|a, b| compare_names(&a.name, &b.name));
         Ok(stubs)
     }
 
+    fn meta_path(&self) -> PathBuf {
+        let mut meta_path = self.path.clone();
+        meta_path.push(WEB_FILENAME);
+        meta_path
+    }
+
+    pub fn metadata(&self) -> Result<WebMeta, WebError> {
+        if self.storage.refuses_link(&self.meta_path()) {
+            return Err(WebError::Symlink);
+        }
+        let meta_file = match File::open(self.meta_path()) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(self.create_metadata());
+            },
+            Err(err) => return Err(WebError::IoError(err))
+        };
+        let meta = serde_json::from_reader(meta_file)?;
+        Ok(meta)
+    }
+
+    // Webs made before web.json existed get the default one the first
+    // time it is read. If another reader creates it first, theirs is
+    // used; if it cannot be written, the default is still returned.
+    fn create_metadata(&self) -> WebMeta {
+        let meta = WebMeta::new(&self.name);
+        let data = serde_json::to_string_pretty(&meta).unwrap();
+        match self.storage.create_file(&self.meta_path(), data.as_bytes()) {
+            Ok(false) => self.metadata().unwrap_or(meta),
+            Ok(true) => meta,
+            Err(err) => {
+                println!("unable to create {}: {}", self.meta_path().display(), err);
+                meta
+            }
+        }
+    }
+
+    pub fn detail(&self) -> Result<WebDetail, WebError> {
+        let meta = self.metadata()?;
+        let page_count = self.list_pages()?.len();
+        Ok(WebDetail {
+            name: meta.name,
+            title: meta.title,
+            description: meta.description,
+            read_only: meta.read_only,
+            page_count
+        })
+    }
+
+    pub fn stats(&self) -> Result<WebStats, WebError> {
+        let mut stats = WebStats {
+            page_count: 0,
+            attachment_count: 0,
+            total_version_count: 0,
+            total_size_bytes: 0
+        };
+        for entry in fs::read_dir(&self.path)? {
+            let page_path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue
+            };
+            if !page_path.is_dir() || page_path.to_str().is_none() {
+                continue;
+            }
+            if page_path.ends_with(BLOBS_DIRECTORY) {
+                continue;
+            }
+            stats.page_count += 1;
+
+            let page_sizes = file_sizes(&page_path);
+            let version_sizes = file_sizes(&page_path.join(VERSIONS_DIRECTORY));
+            let attachment_sizes = file_sizes(&self.storage.attachments_path(&page_path));
+            stats.total_version_count += version_sizes.len();
+            stats.attachment_count += attachment_sizes.len();
+            stats.total_size_bytes += page_sizes.iter().
+                chain(version_sizes.iter()).
+                chain(attachment_sizes.iter()).
+                sum::<u64>();
+        }
+        stats.total_size_bytes += file_sizes(&self.storage.blobs_path(&self.path)).iter().sum::<u64>();
+        Ok(stats)
+    }
+
+    // Remove version files of page directories that have no detail file,
+    // blobs that no attachment points at, and resumable uploads that
+    // have stopped. Live pages and their versions are never touched.
+    pub fn gc(&self, report: &mut GcReport) -> Result<(), WebError> {
+        let grace = Duration::from_secs(GC_GRACE_PERIOD_SECS);
+        let mut referenced = HashSet::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let page_path = entry.path();
+            if !page_path.is_dir() || entry.file_name() == BLOBS_DIRECTORY {
+                continue;
+            }
+            let attachments_path = self.storage.attachments_path(&page_path);
+            if let Ok(attachments) = fs::read_dir(&attachments_path) {
+                for attachment in attachments.filter_map(|attachment| attachment.ok()) {
+                    if let Some(hash) = read_pointer(&attachment.path()) {
+                        referenced.insert(hash);
+                    }
+                }
+            }
+            let (count, bytes) = remove_stale_uploads(&attachments_path, grace);
+            report.uploads_removed += count;
+            report.bytes_reclaimed += bytes;
+            let live = [JSON_FILENAME, MARKDOWN_FILENAME].iter().any(|name| page_path.join(name).is_file());
+            if !live {
+                let (count, bytes) = remove_stale_files(&page_path.join(VERSIONS_DIRECTORY), grace, |_| true);
+                report.versions_removed += count;
+                report.bytes_reclaimed += bytes;
+            }
+        }
+
+        let blobs_path = self.storage.blobs_path(&self.path);
+        let (count, bytes) = remove_stale_files(&blobs_path, grace, |name| !referenced.contains(name));
+        report.blobs_removed += count;
+        report.bytes_reclaimed += bytes;
+        Ok(())
+    }
+
+    pub fn write_metadata(&self, meta: &WebMeta) -> Result<(), WebError> {
+        let data = serde_json::to_string_pretty(meta)?;
+        self.storage.write_file(&self.meta_path(), data.as_ref())?;
+        Ok(())
+    }
+
+    // Only stats the page directory and its detail file; the detail
+    // itself is not parsed.
+    pub fn page_exists(&self, name: &str) -> bool {
+        let mut path = self.path.clone();
+        path.push(name);
+        if !path.is_dir() {
+            return false;
+        }
+        path.push(self.storage.store().file_name());
+        path.is_file()
+    }
+
+    // Write the whole web directory (pages, versions and attachments) as
+    // a tar archive rooted at the web's name.
+    pub fn export<W: Write>(&self, out: W) -> Result<(), WebError> {
+        let mut builder = tar::Builder::new(out);
+        builder.follow_symlinks(false);
+        builder.append_dir_all(&self.name, &self.path)?;
+        // relocated attachments are archived in the in-web layout
+        if self.storage.attachments_root.is_some() {
+            for stub in self.list_pages()? {
+                let attachments_path = self.storage.attachments_path(&self.path.join(&stub.name));
+                if attachments_path.is_dir() {
+                    let archive_path = Path::new(&self.name).join(&stub.name).join(ATTACHMENTS_DIRECTORY);
+                    builder.append_dir_all(archive_path, attachments_path)?;
+                }
+            }
+            let blobs_path = self.storage.blobs_path(&self.path);
+            if blobs_path.is_dir() {
+                builder.append_dir_all(Path::new(&self.name).join(BLOBS_DIRECTORY), blobs_path)?;
+            }
+        }
+        builder.into_inner()?;
+        Ok(())
+    }
+
+    // One JSON page detail per line, reading each page only as it is
+    // written. A page that fails to open gets an error line instead;
+    // directories with no detail file are not pages and are skipped.
+    pub fn write_ndjson<W: Write>(&self, mut out: W) -> Result<(), WebError> {
+        for stub in self.list_pages()? {
+            let line = match self.get_page(&stub.name) {
+                Ok(page) => serde_json::to_string(&page.detail)?,
+                Err(PageError::NotFound) => continue,
+                Err(err) => serde_json::to_string(&PageLineError { page: stub.name, error: err.to_string() })?
+            };
+            out.write_all(line.as_bytes())?;
+            out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    // Pages that fail to open are skipped rather than failing the search.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>, WebError> {
+        let query = query.to_lowercase();
+        let mut hits = Vec::new();
+        for stub in self.list_pages()? {
+            if hits.len() >= limit {
+                break;
+            }
+            if let Ok(page) = self.get_page(&stub.name) {
+                if page.detail.matches(&query) {
+                    hits.push(SearchHit { page: stub.name, title: page.detail.title });
+                }
+            }
+        }
+        Ok(hits)
+    }
+
+    // Links are checked against this web unless they have the form
+    // `Web.Page` and `Web` exists, as with page parents. Pages that fail
+    // to open are skipped.
+    pub fn broken_links(&self) -> Result<Vec<BrokenLink>, WebError> {
+        let mut broken = Vec::new();
+        for stub in self.list_pages()? {
+            let page = match self.get_page(&stub.name) {
+                Ok(page) => page,
+                Err(_) => continue
+            };
+            for (line, target) in wiki_links(page.detail.content()) {
+                if !self.link_exists(&target) {
+                    broken.push(BrokenLink { source_page: stub.name.clone(), target, line });
+                }
+            }
+        }
+        Ok(broken)
+    }
+
+    // Pages in this web linking to `page_name`, either by name or as
+    // `Web.Page`. Pages that fail to open are skipped.
+    pub fn backlinks(&self, page_name: &str) -> Result<Vec<String>, WebError> {
+        let qualified = format!("{}.{}", self.name, page_name);
+        let mut sources = Vec::new();
+        for stub in self.list_pages()? {
+            let page = match self.get_page(&stub.name) {
+                Ok(page) => page,
+                Err(_) => continue
+            };
+            if page.outbound_links().iter().any(|target| *target == page_name || *target == qualified) {
+                sources.push(stub.name);
+            }
+        }
+        Ok(sources)
+    }
+
+    // Every page that opens, and an edge for each distinct link from it.
+    // Links to `Web.Page` in this web are given as just the page name;
+    // edges whose target does not exist are marked dangling.
+    pub fn link_graph(&self) -> Result<LinkGraph, WebError> {
+        let prefix = format!("{}.", self.name);
+        let mut graph = LinkGraph { nodes: Vec::new(), edges: Vec::new() };
+        for stub in self.list_pages()? {
+            let page = match self.get_page(&stub.name) {
+                Ok(page) => page,
+                Err(_) => continue
+            };
+            for target in page.outbound_links() {
+                let dangling = !self.link_exists(&target);
+                let to = match target.strip_prefix(&prefix) {
+                    Some(name) if !name.is_empty() => name.to_string(),
+                    _ => target
+                };
+                graph.edges.push(GraphEdge { from: stub.name.clone(), to, dangling });
+            }
+            graph.nodes.push(stub.name);
+        }
+        Ok(graph)
+    }
+
+    pub fn link_exists(&self, target: &str) -> bool {
+        if let Some(dot) = target.find('.') {
+            let (other_web, page) = (&target[..dot], &target[dot + 1..]);
+            if let Some(parent) = self.path.parent() {
+                let path = parent.join(other_web);
+                if is_valid_name(other_web) && !page.is_empty() && path.is_dir() && !self.storage.refuses_link(&path) {
+                    let web = Web { name: other_web.to_string(), path, storage: self.storage.clone() };
+                    return is_valid_name(page) && web.page_exists(page);
+                }
+            }
+        }
+        is_valid_name(target) && self.page_exists(target)
+    }
+
+    // Only pages with a detail file count; their versions and
+    // attachments do not.
+    pub fn page_count(&self) -> Result<usize, WebError> {
+        let count = self.list_pages()?.iter().filter(|stub| self.page_exists(&stub.name)).count();
+        Ok(count)
+    }
+
+    // Whether one more page may be created, given the server-wide limit.
+    // The web's own `max_pages` takes precedence when set.
+    pub fn check_page_quota(&self, default_limit: Option<usize>) -> Result<(), PageError> {
+        let meta = self.metadata().map_err(|_| PageError::InvalidPath)?;
+        let limit = match meta.max_pages.or(default_limit) {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+        let count = self.page_count().map_err(|_| PageError::InvalidPath)?;
+        if count >= limit {
+            return Err(PageError::QuotaExceeded(limit));
+        }
+        Ok(())
+    }
+
+    // Create `new_name` in `target`, which may be this web, with the
+    // page's current detail and attachments. Past versions come along
+    // only `with_history`. The copy keeps the original's modification
+    // time, since its content has not changed.
+    pub fn copy_page(&self, page_name: &str, target: &Web, new_name: &str, with_history: bool) -> Result<Page, PageError> {
+        let source = self.get_page(page_name)?;
+        let mut detail = source.detail.clone();
+        detail.name = new_name.to_string();
+        let page = target.new_page(detail);
+        page.create()?;
+
+        if with_history {
+            copy_files(&source.path.join(VERSIONS_DIRECTORY), &page.path.join(VERSIONS_DIRECTORY))?;
+        }
+
+        let attachments_path = self.storage.attachments_path(&source.path);
+        if attachments_path.is_dir() {
+            let blobs_path = self.storage.blobs_path(&self.path);
+            let target_blobs_path = target.storage.blobs_path(&target.path);
+            for entry in fs::read_dir(&attachments_path)? {
+                let path = entry?.path();
+                if let Some(hash) = read_pointer(&path) {
+                    let blob_path = target_blobs_path.join(&hash);
+                    if !blob_path.exists() && blobs_path.join(&hash).is_file() {
+                        fs::create_dir_all(&target_blobs_path)?;
+                        fs::copy(blobs_path.join(&hash), blob_path)?;
+                    }
+                }
+            }
+            copy_files(&attachments_path, &target.storage.attachments_path(&page.path))?;
+        }
+
+        if let Some(modified) = self.page_modified(page_name) {
+            let file_name = page.path.join(target.storage.store().file_name());
+            fs::OpenOptions::new().write(true).open(file_name)?.set_modified(modified)?;
+        }
+        Ok(page)
+    }
+
+    // Bring a page's detail name and directory name back into agreement,
+    // changing whichever `strategy` says. A page that already agrees is
+    // left alone.
+    pub fn repair_page(&self, page_name: &str, strategy: RepairStrategy) -> Result<PageRepair, PageError> {
+        let path = self.path.join(page_name);
+        if !path.is_dir() {
+            return Err(PageError::NotFound);
+        }
+        let mut data = Vec::new();
+        File::open(path.join(self.storage.store().file_name()))?.read_to_end(&mut data)?;
+        let mut detail = self.storage.store().decode(&data)?;
+        let mut repair = PageRepair {
+            strategy: strategy.as_str(),
+            changed: detail.name != page_name,
+            from: detail.name.clone(),
+            to: page_name.to_string(),
+            hash: None
+        };
+        if !repair.changed {
+            return Ok(repair);
+        }
+
+        match strategy {
+            RepairStrategy::Name => {
+                detail.name = page_name.to_string();
+                let page = Page { path, detail, storage: self.storage.clone() };
+                repair.hash = Some(page.update()?);
+            },
+            RepairStrategy::Directory => {
+                repair.from = page_name.to_string();
+                repair.to = detail.name.clone();
+                if !is_valid_name(&detail.name) {
+                    return Err(PageError::InvalidName);
+                }
+                let new_path = self.path.join(&detail.name);
+                if new_path.exists() {
+                    return Err(PageError::OverwriteError);
+                }
+                let attachments_path = self.storage.attachments_path(&path);
+                fs::rename(&path, &new_path)?;
+                // relocated attachments are keyed by page name too
+                if self.storage.attachments_root.is_some() && attachments_path.exists() {
+                    fs::rename(&attachments_path, self.storage.attachments_path(&new_path))?;
+                }
+            }
+        }
+        Ok(repair)
+    }
+
+    pub fn index_page(&self) -> Result<String, WebError> {
+        let meta = self.metadata()?;
+        Ok(meta.index.unwrap_or_else(|| DEFAULT_INDEX_PAGE.to_string()))
+    }
+
+    pub fn page_modified(&self, name: &str) -> Option<SystemTime> {
+        let mut path = self.path.clone();
+        path.push(name);
+        path.push(self.storage.store().file_name());
+        fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()
+    }
+
     pub fn get_page(&self, name: &str) -> Result<Page, PageError> {
         let mut path = self.path.clone();
         path.push(name);
-        Page::open(path)
+        Page::open(path, self.storage.clone())
+    }
+
+    // Resolve a page name ignoring case, for when the exact name does not
+    // exist. Names differing only in case are reported as ambiguous.
+    pub fn find_page_name(&self, name: &str) -> Result<String, PageError> {
+        let lowercase = name.to_lowercase();
+        let mut names: Vec<String> = self.list_pages().map_err(|_| PageError::NotFound)?.
+            into_iter().
+            map(|stub| stub.name).
+            filter(|candidate| candidate.to_lowercase() == lowercase && self.page_exists(candidate)).
+            collect();
+        match names.len() {
+            0 => Err(PageError::NotFound),
+            1 => Ok(names.remove(0)),
+            _ => {
+                names.sort();
+                Err(PageError::Ambiguous(names))
+            }
+        }
+    }
+
+    // Existing pages with names close to `name`, nearest first. Case is
+    // ignored when comparing.
+    pub fn suggest(&self, name: &str, max: usize) -> Result<Vec<String>, WebError> {
+        let name: Vec<char> = name.to_lowercase().chars().collect();
+        if name.is_empty() || name.len() > SUGGEST_MAX_LENGTH || max == 0 {
+            return Ok(Vec::new());
+        }
+        let limit = (name.len() / 3).max(1).min(SUGGEST_MAX_DISTANCE);
+        let mut close: Vec<(usize, String)> = Vec::new();
+        for stub in self.list_pages()? {
+            let candidate: Vec<char> = stub.name.to_lowercase().chars().collect();
+            if let Some(distance) = edit_distance(&name, &candidate, limit) {
+                if self.page_exists(&stub.name) {
+                    close.push((distance, stub.name));
+                }
+            }
+        }
+        close.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| compare_names(&a.1, &b.1)));
+        Ok(close.into_iter().take(max).map(|(_, name)| name).collect())
     }
 
     pub fn new_page(&self, detail: PageDetail) -> Page {
         let mut path = self.path.clone();
         path.push(&detail.name);
-        Page { path, detail }
+        Page { path, detail, storage: self.storage.clone() }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Default)]
+pub struct GcReport {
+    pub versions_removed: usize,
+    pub blobs_removed: usize,
+    pub uploads_removed: usize,
+    pub bytes_reclaimed: u64
+}
+
+#[derive(Serialize)]
 pub struct WebStub {
     pub name: String
 }
 
-impl WebStub {
-    pub fn parse(data: &[u8]) -> Result<WebStub, WebError> {
-        let stub = serde_json::from_slice::<WebStub>(&data)?;
-        Ok(stub)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WebSort {
+    Name,
+    Created
+}
+
+impl WebSort {
+    pub fn parse(key: &str) -> Option<WebSort> {
+        match key {
+            "name" => Some(WebSort::Name),
+            "created" => Some(WebSort::Created),
+            _ => None
+        }
+    }
+}
+
+// How `Webs::list_webs` orders and slices its result. The default lists
+// every web by name, ascending.
+pub struct ListSpec {
+    pub sort: WebSort,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: Option<usize>
+}
+
+impl Default for ListSpec {
+    fn default() -> ListSpec {
+        ListSpec { sort: WebSort::Name, descending: false, offset: 0, limit: None }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WebList {
+    pub total: usize,
+    pub webs: Vec<WebStub>
+}
+
+#[derive(Deserialize)]
+pub struct WebData {
+    pub name: String,
+    pub title: Option<String>,
+    pub description: Option<String>
+}
+
+impl WebData {
+    pub fn parse(data: &[u8]) -> Result<WebData, WebError> {
+        let web_data = serde_json::from_slice::<WebData>(&data)?;
+        Ok(web_data)
+    }
+}
+
+// `target_web` and `new_name` default to the page's own web and name.
+#[derive(Deserialize)]
+pub struct CopyData {
+    #[serde(default)]
+    pub target_web: Option<String>,
+    #[serde(default)]
+    pub new_name: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct CloneData {
+    pub new_name: String
+}
+
+impl CloneData {
+    pub fn parse(data: &[u8]) -> Result<CloneData, WebError> {
+        let clone_data = serde_json::from_slice::<CloneData>(data)?;
+        Ok(clone_data)
     }
 }
 
+impl CopyData {
+    pub fn parse(data: &[u8]) -> Result<CopyData, WebError> {
+        let copy_data = serde_json::from_slice::<CopyData>(data)?;
+        Ok(copy_data)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WebMeta {
+    pub name: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub read_only: bool,
+    // overrides the server-wide limit on pages in this web
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pages: Option<usize>,
+    // the landing page, `WebHome` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>
+}
+
+impl WebMeta {
+    pub fn new(name: &str) -> WebMeta {
+        WebMeta {
+            name: name.to_string(),
+            title: name.to_string(),
+            description: String::new(),
+            read_only: false,
+            max_pages: None,
+            index: None
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WebDetail {
+    pub name: String,
+    pub title: String,
+    pub description: String,
+    pub read_only: bool,
+    pub page_count: usize
+}
+
+#[derive(Deserialize)]
+pub struct ReadOnlyData {
+    pub read_only: bool
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Debug)]
+pub struct PageRef {
+    pub web: String,
+    pub page: String
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub page: String,
+    pub title: String
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RepairStrategy {
+    // rewrite the detail's name to match the directory
+    Name,
+    // rename the directory to match the detail
+    Directory
+}
+
+impl RepairStrategy {
+    pub fn parse(name: &str) -> Option<RepairStrategy> {
+        match name {
+            "name" => Some(RepairStrategy::Name),
+            "directory" => Some(RepairStrategy::Directory),
+            _ => None
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            RepairStrategy::Name => "name",
+            RepairStrategy::Directory => "directory"
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PageRepair {
+    pub strategy: &'static str,
+    pub changed: bool,
+    pub from: String,
+    pub to: String,
+    // the version written when the detail was rewritten
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>
+}
+
+#[derive(Serialize)]
+struct PageLineError {
+    page: String,
+    error: String
+}
+
+#[derive(Serialize)]
+pub struct BrokenLink {
+    pub source_page: String,
+    pub target: String,
+    pub line: usize
+}
+
+#[derive(Serialize)]
+pub struct OutboundLink {
+    pub target: String,
+    pub exists: bool
+}
+
+#[derive(Serialize)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    pub dangling: bool
+}
+
+#[derive(Serialize)]
+pub struct LinkGraph {
+    pub nodes: Vec<String>,
+    pub edges: Vec<GraphEdge>
+}
+
+#[derive(Serialize)]
+pub struct PageLinks {
+    pub outbound: Vec<OutboundLink>,
+    pub inbound: Vec<String>
+}
+
+#[derive(Serialize)]
+pub struct GlobalSearchHit {
+    pub web: String,
+    pub page: String,
+    pub title: String
+}
+
+#[derive(Serialize)]
+pub struct WebStats {
+    pub page_count: usize,
+    pub attachment_count: usize,
+    pub total_version_count: usize,
+    pub total_size_bytes: u64
+}
+
+#[derive(Serialize)]
+pub struct ImportSummary {
+    pub pages: usize,
+    pub attachments: usize
+}
+
+// The Levenshtein distance between `a` and `b`, or `None` if it is more
+// than `limit`. Only cells within `limit` of the diagonal are computed.
+fn edit_distance(a: &[char], b: &[char], limit: usize) -> Option<usize> {
+    if b.len() > SUGGEST_MAX_LENGTH || a.len().max(b.len()) - a.len().min(b.len()) > limit {
+        return None;
+    }
+    let over = limit + 1;
+    let mut previous: Vec<usize> = (0..b.len() + 1).map(|j| j.min(over)).collect();
+    let mut current = vec![over; b.len() + 1];
+    for i in 1..a.len() + 1 {
+        let start = if i > limit { i - limit } else { 1 };
+        let end = (i + limit).min(b.len());
+        for cell in current.iter_mut() {
+            *cell = over;
+        }
+        if i <= limit {
+            current[0] = i;
+        }
+        for j in start..end + 1 {
+            let substitution = previous[j - 1] + if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1).min(over);
+        }
+        if current.iter().all(|&cell| cell > limit) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    Some(previous[b.len()]).filter(|&distance| distance <= limit)
+}
+
+// Copy the regular files directly inside `from` into `to`, creating it
+// if needed. Files already in `to` and temporary files are left alone.
+fn copy_files(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let target_path = to.join(entry.file_name());
+        if !target_path.exists() {
+            fs::copy(&path, target_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Copy a directory tree a file at a time, without reading whole files
+// into memory. Hidden files, which are unfinished writes and uploads,
+// symbolic links and directories that `skip` accepts are left out.
+fn copy_tree<F: Fn(&Path) -> bool>(from: &Path, to: &Path, skip: &F) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let target_path = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !skip(&path) {
+                copy_tree(&path, &target_path, skip)?;
+            }
+        } else if file_type.is_file() {
+            fs::copy(&path, &target_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Delete the files in `path` older than `grace` whose names pass
+// `collect`, returning how many were removed and their total size.
+// Temporary files from in-progress atomic writes are skipped.
+fn remove_stale_files<F: Fn(&str) -> bool>(path: &Path, grace: Duration, collect: F) -> (usize, u64) {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return (0, 0)
+    };
+    let mut count = 0;
+    let mut bytes = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue
+        };
+        if name.starts_with('.') || !collect(&name) {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue
+        };
+        let stale = metadata.modified().ok().
+            and_then(|modified| modified.elapsed().ok()).
+            map_or(false, |age| age >= grace);
+        if metadata.is_file() && stale && fs::remove_file(entry.path()).is_ok() {
+            count += 1;
+            bytes += metadata.len();
+        }
+    }
+    (count, bytes)
+}
+
+// Delete the resumable uploads in `path` that have not grown for
+// `grace`, with their recorded totals, returning how many were removed
+// and their total size. Totals left without a part file go too.
+fn remove_stale_uploads(path: &Path, grace: Duration) -> (usize, u64) {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return (0, 0)
+    };
+    let mut count = 0;
+    let mut bytes = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue
+        };
+        let is_part = match upload_part_name(&name) {
+            Some(part_name) if part_name == name => true,
+            Some(part_name) if !path.join(part_name).exists() => false,
+            _ => continue
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue
+        };
+        let stale = metadata.modified().ok().
+            and_then(|modified| modified.elapsed().ok()).
+            map_or(false, |age| age >= grace);
+        if metadata.is_file() && stale && fs::remove_file(entry.path()).is_ok() && is_part {
+            let _ = fs::remove_file(path.join(total_file_name(&name)));
+            count += 1;
+            bytes += metadata.len();
+        }
+    }
+    (count, bytes)
+}
+
+// Sizes of the regular files directly inside `path`; anything that
+// cannot be read is skipped.
+fn file_sizes(path: &Path) -> Vec<u64> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    entries.filter_map(|entry| {
+        entry.ok().and_then(|entry| entry.metadata().ok())
+    }).filter(|metadata| metadata.is_file()).map(|metadata| metadata.len()).collect()
+}
+
+// Archives are rooted at a single directory (the exported web's name),
+// which is stripped off. Anything other than plain relative paths to
+// regular files and directories is treated as hostile.
+fn archive_entry_path<R: Read>(entry: &tar::Entry<R>) -> Result<PathBuf, WebError> {
+    match entry.header().entry_type() {
+        tar::EntryType::Regular | tar::EntryType::Directory => (),
+        _ => return Err(WebError::InvalidArchive)
+    }
+    let path = entry.path().map_err(|_| WebError::InvalidArchive)?;
+    let mut relative = PathBuf::new();
+    for (i, component) in path.components().enumerate() {
+        match component {
+            Component::Normal(part) => {
+                if i > 0 {
+                    relative.push(part);
+                }
+            },
+            _ => return Err(WebError::InvalidArchive)
+        }
+    }
+    Ok(relative)
+}
+
 pub struct Webs {
-    pub path: PathBuf
+    pub path: PathBuf,
+    pub storage: Storage
 }
 
 impl Webs {
+    pub fn is_ready(&self) -> bool {
+        self.path.is_dir() && fs::read_dir(&self.path).is_ok()
+    }
+
+    // Names can come from request bodies as well as paths, so one that
+    // could lead outside the data directory is never looked up.
     pub fn get_web(&self, name: &str) -> Option<Web> {
+        if !is_valid_name(name) {
+            return None;
+        }
         let mut path = self.path.clone();
         path.push(name);
-        if path.is_dir() {
-            Some(Web { name: name.to_string(), path: path })
+        if path.is_dir() && !self.storage.refuses_link(&path) {
+            Some(Web { name: name.to_string(), path: path, storage: self.storage.clone() })
         } else {
             None
         }
     }
 
-    pub fn list_webs(&self) -> Result<Vec<WebStub>, WebError> {
-        let stubs = fs::read_dir(&self.path)?.filter(|entry| {
-            match entry {
-                &Err(_) => false,
-                &Ok(ref entry) => {
-                    let path = entry.path();
-                    if !path.is_dir() {
-                        return false;
+    // Copy a whole web under a new name, with or without page history.
+    // The copy is writable whatever the source's read-only flag says. A
+    // copy that fails part way is removed.
+    pub fn clone_web(&self, src: &str, dst: &str, with_history: bool) -> Result<Web, WebError> {
+        let source = self.get_web(src).ok_or(WebError::NotFound)?;
+        if !is_valid_name(dst) {
+            return Err(WebError::InvalidName);
+        }
+        let path = self.path.join(dst);
+        match self.storage.create_dir(&path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(WebError::OverwriteError);
+            },
+            Err(err) => return Err(err.into())
+        }
+        let web = Web { name: dst.to_string(), path, storage: self.storage.clone() };
+
+        // relocated attachments and blobs mirror the web's directory
+        let relocated = self.storage.attachments_root.as_ref().map(|root| (root.join(src), root.join(dst)));
+        let relocated_existed = relocated.as_ref().map_or(false, |&(_, ref to)| to.exists());
+        let result = (|| -> Result<(), WebError> {
+            let skip = |dir: &Path| {
+                !with_history && dir.file_name().map_or(false, |name| name == VERSIONS_DIRECTORY) &&
+                    dir.parent().and_then(|page| page.parent()) == Some(&source.path)
+            };
+            copy_tree(&source.path, &web.path, &skip)?;
+            if let Some((ref from, ref to)) = relocated {
+                if from.is_dir() {
+                    copy_tree(from, to, &|_: &Path| false)?;
+                }
+            }
+            let mut meta = source.metadata()?;
+            if meta.title == meta.name {
+                meta.title = dst.to_string();
+            }
+            meta.name = dst.to_string();
+            meta.read_only = false;
+            web.write_metadata(&meta)
+        })();
+        if let Err(err) = result {
+            let _ = fs::remove_dir_all(&web.path);
+            if let Some((_, ref to)) = relocated {
+                if !relocated_existed {
+                    let _ = fs::remove_dir_all(to);
+                }
+            }
+            return Err(err);
+        }
+        Ok(web)
+    }
+
+    pub fn is_symlinked_web(&self, name: &str) -> bool {
+        self.storage.refuses_link(&self.path.join(name))
+    }
+
+    // A `parent` of the form `Web.Page` names a page in another web when
+    // `Web` exists; anything else is a page in `web_name`.
+    pub fn resolve_parent(&self, web_name: &str, parent: &str) -> Option<PageRef> {
+        if parent.is_empty() {
+            return None;
+        }
+        if let Some(dot) = parent.find('.') {
+            let (other_web, page) = (&parent[..dot], &parent[dot + 1..]);
+            if is_valid_name(other_web) && !page.is_empty() && self.get_web(other_web).is_some() {
+                return Some(PageRef { web: other_web.to_string(), page: page.to_string() });
+            }
+        }
+        Some(PageRef { web: web_name.to_string(), page: parent.to_string() })
+    }
+
+    // Nearest first. The walk stops at a parent that does not exist or
+    // one that has already been visited, so cycles across webs end.
+    pub fn ancestors(&self, web_name: &str, page_name: &str) -> Result<Vec<PageRef>, PageError> {
+        let page = match self.get_web(web_name) {
+            Some(web) => web.get_page(page_name)?,
+            None => return Err(PageError::NotFound)
+        };
+        let mut visited = HashSet::new();
+        visited.insert(PageRef { web: web_name.to_string(), page: page_name.to_string() });
+
+        let mut ancestors = Vec::new();
+        let mut current = self.resolve_parent(web_name, page.detail.parent());
+        while let Some(parent) = current.take() {
+            if visited.contains(&parent) {
+                break;
+            }
+            let page = match self.get_web(&parent.web).map(|web| web.get_page(&parent.page)) {
+                Some(Ok(page)) => page,
+                _ => break
+            };
+            current = self.resolve_parent(&parent.web, page.detail.parent());
+            visited.insert(parent.clone());
+            ancestors.push(parent);
+        }
+        Ok(ancestors)
+    }
+
+    // Pages in any web whose parent resolves to the given page. Pages
+    // that fail to open are skipped.
+    pub fn children(&self, web_name: &str, page_name: &str) -> Result<Vec<PageRef>, WebError> {
+        let target = PageRef { web: web_name.to_string(), page: page_name.to_string() };
+        let mut children = Vec::new();
+        for stub in self.list_webs(&ListSpec::default())?.webs {
+            let web = match self.get_web(&stub.name) {
+                Some(web) => web,
+                None => continue
+            };
+            for page_stub in web.list_pages()? {
+                if let Ok(page) = web.get_page(&page_stub.name) {
+                    if self.resolve_parent(&web.name, page.detail.parent()).as_ref() == Some(&target) {
+                        children.push(PageRef { web: web.name.clone(), page: page_stub.name });
                     }
-                    let s = path.to_str();
-                    s.is_some()
                 }
             }
-        }).map(|entry| {
-            let name = entry.unwrap().path().file_name().unwrap().to_str().unwrap().to_string();
-            WebStub { name }
+        }
+        Ok(children)
+    }
+
+    pub fn gc(&self) -> Result<GcReport, WebError> {
+        let mut report = GcReport::default();
+        for stub in self.list_webs(&ListSpec::default())?.webs {
+            if let Some(web) = self.get_web(&stub.name) {
+                web.gc(&mut report)?;
+            }
+        }
+        Ok(report)
+    }
+
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<GlobalSearchHit>, WebError> {
+        let mut hits = Vec::new();
+        for stub in self.list_webs(&ListSpec::default())?.webs {
+            if hits.len() >= limit {
+                break;
+            }
+            let web = match self.get_web(&stub.name) {
+                Some(web) => web,
+                None => continue
+            };
+            for hit in web.search(query, limit - hits.len())? {
+                hits.push(GlobalSearchHit { web: web.name.clone(), page: hit.page, title: hit.title });
+            }
+        }
+        Ok(hits)
+    }
+
+    // Creation order uses the directory mtime, falling back to the epoch
+    // when it cannot be read. Ties are broken by name.
+    pub fn list_webs(&self, spec: &ListSpec) -> Result<WebList, WebError> {
+        let mut entries: Vec<(String, SystemTime)> = fs::read_dir(&self.path)?.filter_map(|entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => return None
+            };
+            let path = entry.path();
+            if !path.is_dir() || self.storage.refuses_link(&path) {
+                return None;
+            }
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => return None
+            };
+            let created = fs::metadata(&path).and_then(|metadata| metadata.modified()).
+                unwrap_or(UNIX_EPOCH);
+            Some((name, created))
         }).collect();
-        Ok(stubs)
+
+        match spec.sort {
+            WebSort::Name => entries.sort_by(|a, b| compare_names(&a.0, &b.0)),
+            WebSort::Created => entries.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| compare_names(&a.0, &b.0)))
+        }
+        if spec.descending {
+            entries.reverse();
+        }
+
+        let total = entries.len();
+        let limit = spec.limit.unwrap_or(total);
+        let webs = entries.into_iter().
+            skip(spec.offset).
+            take(limit).
+            map(|(name, _)| WebStub { name }).
+            collect();
+        Ok(WebList { total, webs })
     }
 
-    pub fn create_web(&self, name: &str) -> Result<Web, WebError> {
+    // Unpack a tar archive (as produced by `Web::export`) into the named
+    // web. Every entry is validated before anything is written.
+    pub fn import_web(&self, name: &str, archive: &[u8], overwrite: bool) -> Result<ImportSummary, WebError> {
+        if !is_valid_name(name) {
+            return Err(WebError::InvalidName);
+        }
+        let mut web_path = self.path.clone();
+        web_path.push(name);
+        if web_path.exists() && !overwrite {
+            return Err(WebError::OverwriteError);
+        }
+
+        {
+            let mut entries = tar::Archive::new(archive);
+            for entry in entries.entries().map_err(|_| WebError::InvalidArchive)? {
+                let entry = entry.map_err(|_| WebError::InvalidArchive)?;
+                archive_entry_path(&entry)?;
+            }
+        }
+
+        if !web_path.exists() {
+            fs::create_dir(&web_path)?;
+        }
+        let mut summary = ImportSummary { pages: 0, attachments: 0 };
+        let mut entries = tar::Archive::new(archive);
+        for entry in entries.entries()? {
+            let mut entry = entry?;
+            let relative = archive_entry_path(&entry)?;
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+
+            let dest = self.storage.unpack_path(&web_path, &relative);
+            if entry.header().entry_type() == tar::EntryType::Directory {
+                fs::create_dir_all(&dest)?;
+                continue;
+            }
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = File::create(&dest)?;
+            io::copy(&mut entry, &mut file)?;
+
+            let parts: Vec<&Path> = relative.ancestors().collect();
+            let is_page_file = relative.ends_with(JSON_FILENAME) || relative.ends_with(MARKDOWN_FILENAME);
+            if is_page_file && parts.len() == 3 {
+                summary.pages += 1;
+            } else if parts.len() == 4 && relative.parent().unwrap().ends_with(ATTACHMENTS_DIRECTORY) {
+                summary.attachments += 1;
+            }
+        }
+
+        // the archive may come from a web with a different name
+        let web = Web { name: name.to_string(), path: web_path, storage: self.storage.clone() };
+        let mut meta = web.metadata()?;
+        meta.name = web.name.clone();
+        web.write_metadata(&meta)?;
+        Ok(summary)
+    }
+
+    // Give an empty wiki directory a Home web with a WebHome page. A
+    // directory that already has entries is left alone.
+    pub fn scaffold(&self) -> Result<(), WebError> {
+        let is_empty = fs::read_dir(&self.path)?.filter_map(|entry| entry.ok()).
+            all(|entry| entry.file_name().to_string_lossy().starts_with('.'));
+        if !is_empty {
+            return Ok(());
+        }
+
+        let data = WebData { name: SCAFFOLD_WEB.to_string(), title: None, description: None };
+        let web = self.create_web(&data)?;
+        let detail = PageDetail::new(
+            SCAFFOLD_PAGE.to_string(),
+            "Welcome".to_string(),
+            "# Welcome\n\nThis is the home page of your new wiki.\n".to_string(),
+            String::new(),
+            Vec::new()
+        );
+        web.new_page(detail).create()?;
+        Ok(())
+    }
+
+    pub fn create_web(&self, data: &WebData) -> Result<Web, WebError> {
+        let name = &data.name;
+        if !is_valid_name(name) {
+            return Err(WebError::InvalidName);
+        }
         let mut path = self.path.clone();
         path.push(name);
-        if path.exists() {
-            Err(WebError::OverwriteError)
-        } else {
-            fs::create_dir(&path)?;
-            Ok(Web { name: name.to_string(), path: path })
+        // let create_dir decide, so two racing requests cannot both succeed
+        match self.storage.create_dir(&path) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(WebError::OverwriteError);
+            },
+            Err(err) => return Err(err.into())
+        }
+
+        let web = Web { name: name.to_string(), path: path, storage: self.storage.clone() };
+        let mut meta = WebMeta::new(name);
+        if let Some(ref title) = data.title {
+            meta.title = title.clone();
+        }
+        if let Some(ref description) = data.description {
+            meta.description = description.clone();
         }
+        web.write_metadata(&meta)?;
+        Ok(web)
     }
 }